@@ -0,0 +1,287 @@
+// Proc-macro front end for expect-tests. Unlike the `macro_rules!` version in
+// expect-tests, these macros record the source spans of the actual expression
+// and of every expected literal, so the runtime can patch them directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::{LineColumn, Span, TokenStream as TokenStream2};
+use quote::{quote, ToTokens};
+use syn::{
+    parse::{ParseStream, Parser},
+    punctuated::Punctuated,
+    Expr, ExprLit, Ident, ItemFn, Lit, LitStr, Token,
+};
+
+struct ExpectInput {
+    /// The key of `expect!(key => actual, "key" => "expected")`
+    key: Option<Expr>,
+    actual: Option<Expr>,
+    expected_keys: Option<Vec<LitStr>>,
+    expected: Vec<LitStr>,
+}
+
+impl ExpectInput {
+    fn parse_with_actual(input: ParseStream) -> syn::Result<ExpectInput> {
+        let first = input.parse()?;
+        let (key, actual) = if input.parse::<Option<Token![=>]>>()?.is_some() {
+            (Some(first), input.parse()?)
+        } else {
+            (None, first)
+        };
+        if !input.is_empty() {
+            input.parse::<Token![,]>()?;
+        }
+        let (expected_keys, expected) = parse_expected(input)?;
+        if key.is_none() && expected_keys.is_some() {
+            return Err(input.error("keyed expected literals need a `key => actual` first"));
+        }
+        Ok(ExpectInput {
+            expected_keys: expected_keys.or_else(|| key.as_ref().map(|_| Vec::new())),
+            key,
+            actual: Some(actual),
+            expected,
+        })
+    }
+
+    fn parse_unordered(input: ParseStream) -> syn::Result<ExpectInput> {
+        let span = input.span();
+        let input = ExpectInput::parse_with_actual(input)?;
+        if input.expected_keys.is_some() {
+            return Err(syn::Error::new(span, "unordered expects don't take keys"));
+        }
+        Ok(input)
+    }
+
+    fn parse_without_actual(input: ParseStream) -> syn::Result<ExpectInput> {
+        let (expected_keys, expected) = parse_expected(input)?;
+        Ok(ExpectInput {
+            key: None,
+            actual: None,
+            expected_keys,
+            expected,
+        })
+    }
+}
+
+/// Parses either plain literals or `"key" => "value"` pairs, returning the keys
+/// separately.
+fn parse_expected(input: ParseStream) -> syn::Result<(Option<Vec<LitStr>>, Vec<LitStr>)> {
+    let parse_pair = |input: ParseStream| {
+        let first = input.parse::<LitStr>()?;
+        if input.parse::<Option<Token![=>]>>()?.is_some() {
+            Ok((Some(first), input.parse::<LitStr>()?))
+        } else {
+            Ok((None, first))
+        }
+    };
+    let pairs = Punctuated::<_, Token![,]>::parse_terminated_with(input, parse_pair)?;
+    let is_keyed = pairs.first().is_some_and(|(key, _)| key.is_some());
+    if let Some((_, value)) = pairs.iter().find(|(key, _)| key.is_some() != is_keyed) {
+        return Err(syn::Error::new(
+            value.span(),
+            "either every expected literal has a key or none of them has",
+        ));
+    }
+    let (keys, expected): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+    let keys = is_keyed.then(|| keys.into_iter().flatten().collect());
+    Ok((keys, expected))
+}
+
+/// Same as the `macro_rules!` `expect!`, with any delimiter and an optional trailing comma.
+#[proc_macro]
+pub fn expect(input: TokenStream) -> TokenStream {
+    expand(
+        input,
+        ExpectInput::parse_with_actual,
+        |key, actual| match (key, is_str_literal(actual)) {
+            (None, true) => quote!(.assert_eq(#actual)),
+            (None, false) => quote!(.assert_debug_eq(#actual)),
+            (Some(key), true) => quote!(.assert_keyed_eq(#key, #actual)),
+            (Some(key), false) => quote!(.assert_keyed_debug_eq(#key, #actual)),
+        },
+    )
+}
+
+/// Same as the `macro_rules!` `expect_unordered!`.
+#[proc_macro]
+pub fn expect_unordered(input: TokenStream) -> TokenStream {
+    expand(input, ExpectInput::parse_unordered, |_, actual| {
+        if is_str_literal(actual) {
+            quote!(.assert_unordered_eq(#actual))
+        } else {
+            quote!(.assert_unordered_debug_eq(#actual))
+        }
+    })
+}
+
+/// Same as the `macro_rules!` `expect_value!`.
+#[proc_macro]
+pub fn expect_value(input: TokenStream) -> TokenStream {
+    expand(input, ExpectInput::parse_without_actual, |_, _| quote!())
+}
+
+/// Same as the `macro_rules!` `expect_tokens!`.
+#[proc_macro]
+pub fn expect_tokens(input: TokenStream) -> TokenStream {
+    expand(input, ExpectInput::parse_with_actual, |key, actual| {
+        let actual = quote!(&::expect_tests::expect_tokens::ExpectTokens::convert(#actual));
+        match key {
+            Some(key) => quote!(.assert_keyed_eq(#key, #actual)),
+            None => quote!(.assert_eq(#actual)),
+        }
+    })
+}
+
+/// Same as `#[test]`, but runs the test inside an `expect_tests::scope::ExpectScope`,
+/// so it fails if an expect with several expected literals doesn't use all of them.
+/// With `#[expect_tests::test(soft)]` the scope is soft, and the test reports
+/// all of its mismatching expects at the end instead of stopping at the first.
+#[proc_macro_attribute]
+pub fn test(args: TokenStream, item: TokenStream) -> TokenStream {
+    let constructor = match syn::parse::<Option<Ident>>(args) {
+        Ok(None) => quote!(new),
+        Ok(Some(ident)) if ident == "soft" => quote!(soft),
+        _ => {
+            return syn::Error::new(
+                Span::call_site(),
+                "`#[expect_tests::test]` only takes `soft` as an argument",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+    let mut function = match syn::parse::<ItemFn>(item) {
+        Ok(function) => function,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    let body = &function.block;
+    *function.block = syn::parse_quote!({
+        let _expect_scope = ::expect_tests::scope::ExpectScope::#constructor();
+        #body
+    });
+    quote!(
+        #[::core::prelude::v1::test]
+        #function
+    )
+    .into()
+}
+
+fn is_str_literal(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Lit(ExprLit {
+            lit: Lit::Str(_),
+            ..
+        })
+    )
+}
+
+fn expand(
+    input: TokenStream,
+    parser: fn(ParseStream) -> syn::Result<ExpectInput>,
+    assertion: impl FnOnce(Option<&Expr>, &Expr) -> TokenStream2,
+) -> TokenStream {
+    let input = match parser.parse(input) {
+        Ok(input) => input,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    let expect = build_expect(&input);
+    let assertion = input
+        .actual
+        .as_ref()
+        .map(|actual| assertion(input.key.as_ref(), actual));
+    quote!(#expect #assertion).into()
+}
+
+fn build_expect(input: &ExpectInput) -> TokenStream2 {
+    let ExpectInput {
+        actual,
+        expected_keys,
+        expected,
+        ..
+    } = input;
+    let raw_actual = match actual {
+        Some(actual) => quote!(Some(stringify!(#actual))),
+        None => quote!(None),
+    };
+    let keys = match expected_keys {
+        Some(keys) => quote!(Some([#(#keys),*])),
+        None => quote!(None),
+    };
+    let spans = match expect_spans(input) {
+        Some(spans) => quote!(Some(#spans)),
+        None => quote!(None),
+    };
+    quote! {
+        {
+            let file_position = ::expect_tests::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            ::expect_tests::expect::Expect {
+                assertion_index: ::expect_tests::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: #raw_actual,
+                expected: [#(#expected),*],
+                raw_expected: [#(stringify!(#expected)),*],
+                keys: #keys,
+                spans: #spans,
+            }
+        }
+    }
+}
+
+fn expect_spans(input: &ExpectInput) -> Option<TokenStream2> {
+    // The runtime finds `key => value` pairs by parsing the invocation instead
+    if input.expected_keys.is_some() {
+        return None;
+    }
+    let call_site = Span::call_site();
+    let invocation = source_span(call_site.start(), call_site.end())?;
+    let actual = match &input.actual {
+        Some(actual) => {
+            let span = tokens_span(actual)?;
+            quote!(Some(#span))
+        }
+        None => quote!(None),
+    };
+    let expected = input
+        .expected
+        .iter()
+        .map(tokens_span)
+        .collect::<Option<Vec<_>>>()?;
+    Some(quote! {
+        ::expect_tests::expect::ExpectSpans {
+            invocation: #invocation,
+            actual: #actual,
+            expected: [#(#expected),*],
+        }
+    })
+}
+
+fn tokens_span(tokens: impl ToTokens) -> Option<TokenStream2> {
+    let tokens = tokens.into_token_stream().into_iter().collect::<Vec<_>>();
+    let start = tokens.first()?.span().start();
+    let end = tokens.last()?.span().end();
+    source_span(start, end)
+}
+
+fn source_span(start: LineColumn, end: LineColumn) -> Option<TokenStream2> {
+    // Compilers that don't expose span locations report everything on line 0
+    if start.line == 0 || end.line == 0 {
+        return None;
+    }
+    // LineColumn columns are zero-indexed while `column!()` is one-indexed
+    let start_line = start.line as u32;
+    let start_column = start.column as u32 + 1;
+    let end_line = end.line as u32;
+    let end_column = end.column as u32 + 1;
+    Some(quote! {
+        ::expect_tests::expect::SourceSpan {
+            start_line: #start_line,
+            start_column: #start_column,
+            end_line: #end_line,
+            end_column: #end_column,
+        }
+    })
+}
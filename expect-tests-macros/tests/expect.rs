@@ -0,0 +1,107 @@
+use expect_tests_macros::{expect, expect_tokens, expect_unordered, expect_value};
+use quote::quote;
+
+#[test]
+fn test_literal() {
+    expect!("ABC", "ABC");
+}
+
+#[test]
+fn test_expression() {
+    let x = 5;
+    expect!(x, "5");
+}
+
+#[test]
+fn test_delimiters_and_trailing_comma() {
+    expect![(1, 2), "(\n    1,\n    2,\n)",];
+    expect! {
+        "ABC\nDEF",
+        r#"
+        ABC
+        DEF"#,
+    }
+}
+
+#[test]
+fn test_multi_expect() {
+    for i in 0..2 {
+        expect!(i, "0", "1");
+    }
+}
+
+#[test]
+fn test_expect_tokens() {
+    expect_tokens!(quote! { struct Unit; }, "struct Unit;\n");
+}
+
+#[test]
+fn test_spans_match_source() {
+    let file = include_str!("expect.rs");
+    let expect = expect_value!("ABC", r#"DEF"#);
+    assert!(expect.spans.is_some());
+    let location = expect.find_expect_location(file).unwrap();
+    expect!(
+        location
+            .expected_ranges
+            .iter()
+            .map(|range| &file[range.clone()])
+            .collect::<Vec<_>>(),
+        r##"
+        [
+            "\"ABC\"",
+            "r#\"DEF\"#",
+        ]"##
+    );
+
+    // Spans must agree with parsing the source file
+    let mut parsed = expect.clone();
+    parsed.spans = None;
+    let parsed_location = parsed.find_expect_location(file).unwrap();
+    assert_eq!(format!("{location:?}"), format!("{parsed_location:?}"));
+}
+
+#[test]
+fn test_spans_empty_invocation() {
+    let file = include_str!("expect.rs");
+    let expect = expect_value!();
+    let location = expect.find_expect_location(file).unwrap();
+    expect!(
+        &file[location.start_index - 1..location.end_index + 1],
+        r#""()""#
+    );
+}
+
+#[expect_tests_macros::test]
+fn test_attribute_scope() {
+    for i in 0..2 {
+        expect!(i, "0", "1");
+    }
+}
+
+#[expect_tests_macros::test]
+fn test_attribute_result() -> Result<(), std::num::ParseIntError> {
+    let x = "5".parse::<i32>()?;
+    expect!(x, "5");
+    Ok(())
+}
+
+#[expect_tests_macros::test(soft)]
+fn test_attribute_soft() {
+    expect!(1, "1");
+    expect!("ABC", "ABC");
+}
+
+#[test]
+fn test_keyed() {
+    for (name, value) in [("b", 2), ("a", 1)] {
+        expect!(name => value, "a" => "1", "b" => "2");
+    }
+}
+
+#[test]
+fn test_unordered() {
+    for value in [2, 1] {
+        expect_unordered!(value, "1", "2");
+    }
+}
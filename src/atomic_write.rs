@@ -0,0 +1,33 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Replaces the contents of `path` without ever leaving a partially written
+/// file behind: the new contents go to a temporary file in the same directory
+/// which is then renamed over the original. Symlinks are followed, so the
+/// link stays in place and its target gets replaced.
+pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let path = std::fs::canonicalize(path)?;
+    let permissions = std::fs::metadata(&path)?.permissions();
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".expect-tests-{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = (|| {
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(contents.as_bytes())?;
+        temp_file.set_permissions(permissions)?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, &path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
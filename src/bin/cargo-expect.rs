@@ -0,0 +1,3 @@
+fn main() {
+    expect_tests::review::main()
+}
@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use crate::{
+    expect::ExpectLocation,
+    patchwork::{PatchOrdering, Patchwork},
+    runtime::format_patch,
+    source_style::SourceStyle,
+    str_lit_kind::StrLitKind,
+};
+
+/// Every change to the expected literals of one macro call. They are applied
+/// together, so the whole call can be laid out again: on a single line while
+/// the arguments fit on one, and with every argument on its own line otherwise.
+#[derive(Debug)]
+pub struct CallEdit {
+    loc: ExpectLocation,
+    /// New literals by index, the ones past the existing literals get appended
+    literals: BTreeMap<usize, Literal>,
+    /// Number of existing literals to keep, the rest are deleted
+    keep: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Layout {
+    /// `expect!(actual, "expected")`
+    Compact,
+    /// Every argument on a line of its own, one level deeper than the call
+    Vertical,
+}
+
+#[derive(Debug)]
+struct Literal {
+    key: Option<String>,
+    value: String,
+}
+
+impl CallEdit {
+    pub fn new(loc: ExpectLocation) -> CallEdit {
+        CallEdit {
+            loc,
+            literals: BTreeMap::new(),
+            keep: None,
+        }
+    }
+
+    pub fn loc(&self) -> &ExpectLocation {
+        &self.loc
+    }
+
+    /// Rewrites (or appends) the expected literal at `index`. A new literal
+    /// of a keyed expect is added as a `key => value` pair.
+    pub fn set(&mut self, index: usize, key: Option<&str>, value: &str) {
+        let literal = Literal {
+            key: key.map(str::to_string),
+            value: value.to_string(),
+        };
+        self.literals.insert(index, literal);
+    }
+
+    /// Deletes the existing literals after the first `count`.
+    pub fn truncate(&mut self, count: usize) {
+        self.keep = Some(count);
+    }
+
+    /// Patches the call, found in `text`, which must be the patchwork's original text.
+    pub fn apply(&self, text: &str, style: &SourceStyle, patchwork: &mut Patchwork) {
+        let loc = &self.loc;
+        // Arguments on lines of their own, and the lines of multiline literals,
+        // are one level deeper than the call
+        let indent = format!("{}{}", loc.line_indent, style.indent);
+        let existing = loc.expected_ranges.len();
+        let keep = self.keep.unwrap_or(existing).min(existing);
+        let replaced = self
+            .literals
+            .range(..keep)
+            .map(|(&index, literal)| {
+                let kind = self.kind_of(text, index, &literal.value, style);
+                (index, format_patch(&indent, kind, &literal.value))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let appended = self
+            .literals
+            .range(existing..)
+            .map(|(_, literal)| format_new(&indent, style, literal))
+            .collect::<Vec<_>>();
+
+        let mut new_args = Vec::new();
+        if let Some(actual_range) = &loc.actual_range {
+            new_args.push(text[loc.start_index..actual_range.end].to_string());
+        }
+        for (index, range) in loc.expected_ranges[..keep].iter().enumerate() {
+            let argument_start = self.argument_start(index);
+            new_args.push(match replaced.get(&index) {
+                Some(patch) => format!("{}{}", &text[argument_start..range.start], patch),
+                None => text[argument_start..range.end].to_string(),
+            });
+        }
+        new_args.extend(appended.iter().cloned());
+
+        // Every argument goes on a line of its own as soon as one of them spans
+        // several lines or they don't fit on one together, otherwise they all share one
+        let compact = new_args.join(", ");
+        let is_multiline = new_args.iter().any(|arg| arg.contains('\n'));
+        let layout = if is_multiline || !self.fits_on_one_line(text, &compact, style) {
+            Layout::Vertical
+        } else {
+            Layout::Compact
+        };
+        if self.layout(text) == Some(layout) || !self.has_only_separators(text) {
+            let is_vertical = self.is_vertical(text);
+            self.apply_in_place(is_vertical, &indent, &replaced, &appended, style, patchwork);
+            return;
+        }
+        let body = match layout {
+            Layout::Vertical => {
+                let arguments = new_args
+                    .iter()
+                    .map(|arg| format!("\n{indent}{arg}"))
+                    .collect::<Vec<_>>();
+                format!("{}\n{}", arguments.join(","), loc.line_indent)
+            }
+            Layout::Compact => compact,
+        };
+        let body = style.with_newlines(&body);
+        patchwork.patch_range(loc.body_range.clone(), &body, PatchOrdering::Normal);
+    }
+
+    /// Patches just the literals, keeping the layout of the call as it is.
+    fn apply_in_place(
+        &self,
+        is_vertical: bool,
+        indent: &str,
+        replaced: &BTreeMap<usize, String>,
+        appended: &[String],
+        style: &SourceStyle,
+        patchwork: &mut Patchwork,
+    ) {
+        let loc = &self.loc;
+        let existing = loc.expected_ranges.len();
+        let keep = self.keep.unwrap_or(existing).min(existing);
+        for (&index, patch) in replaced {
+            let patch = style.with_newlines(patch);
+            patchwork.patch_range(
+                loc.expected_ranges[index].clone(),
+                &patch,
+                PatchOrdering::Normal,
+            );
+        }
+        if keep < existing {
+            let last_kept = match keep.checked_sub(1) {
+                Some(index) => loc.expected_ranges[index].end,
+                None => loc
+                    .actual_range
+                    .as_ref()
+                    .map_or(loc.body_range.start, |range| range.end),
+            };
+            patchwork.patch_range(last_kept..loc.end_index, "", PatchOrdering::Normal);
+        }
+        // `expect_value!()` has no arguments yet, so there is nothing to separate from
+        let mut has_arguments =
+            loc.start_index != loc.end_index && (keep > 0 || loc.actual_range.is_some());
+        for patch in appended {
+            let patch = if !has_arguments {
+                patch.clone()
+            } else if is_vertical || patch.contains('\n') {
+                format!(",\n{indent}{patch}")
+            } else {
+                format!(", {}", patch)
+            };
+            let patch = style.with_newlines(&patch);
+            patchwork.patch_insert(loc.end_index, &patch, PatchOrdering::Normal);
+            has_arguments = true;
+        }
+    }
+
+    /// The kind of literal for the new `value` of the existing literal at
+    /// `index`, which stays the kind it is if it can hold the value.
+    fn kind_of(&self, text: &str, index: usize, value: &str, style: &SourceStyle) -> StrLitKind {
+        StrLitKind::of_literal(&text[self.loc.expected_ranges[index].clone()])
+            .filter(|kind| kind.can_hold(value))
+            .unwrap_or_else(|| StrLitKind::for_new(value, style.literals))
+    }
+
+    /// The ranges of the actual argument and of every expected argument,
+    /// including their keys.
+    fn argument_ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let loc = &self.loc;
+        let expected = loc
+            .expected_ranges
+            .iter()
+            .enumerate()
+            .map(|(index, range)| self.argument_start(index)..range.end);
+        // The first argument starts at the key of a keyed actual
+        let actual = loc
+            .actual_range
+            .as_ref()
+            .map(|range| loc.start_index..range.end);
+        actual.into_iter().chain(expected)
+    }
+
+    /// Where the expected argument at `index` starts, at its key if it has one.
+    fn argument_start(&self, index: usize) -> usize {
+        let range = &self.loc.expected_ranges[index];
+        self.loc
+            .key_ranges
+            .get(index)
+            .map_or(range.start, |key| key.start)
+    }
+
+    /// Whether the arguments are on lines of their own.
+    fn is_vertical(&self, text: &str) -> bool {
+        let first_start = self
+            .argument_ranges()
+            .next()
+            .map_or(self.loc.body_range.end, |range| range.start);
+        text[self.loc.body_range.start..first_start].contains('\n')
+    }
+
+    /// Whether the line of the call stays within `max_width` with `body` between
+    /// its delimiters.
+    fn fits_on_one_line(&self, text: &str, body: &str, style: &SourceStyle) -> bool {
+        let body_range = &self.loc.body_range;
+        let line_start = text[..body_range.start]
+            .rfind('\n')
+            .map_or(0, |index| index + 1);
+        let line_end = text[body_range.end..]
+            .find(['\r', '\n'])
+            .map_or(text.len(), |index| body_range.end + index);
+        let width = style.width(&text[line_start..body_range.start])
+            + style.width(body)
+            + style.width(&text[body_range.end..line_end]);
+        width <= style.max_width
+    }
+
+    /// The current layout of the call, if it is consistent.
+    fn layout(&self, text: &str) -> Option<Layout> {
+        let gaps = self.gaps();
+        if gaps.iter().all(|gap| !text[gap.clone()].contains('\n')) {
+            Some(Layout::Compact)
+        } else if gaps.iter().all(|gap| text[gap.clone()].contains('\n')) {
+            Some(Layout::Vertical)
+        } else {
+            None
+        }
+    }
+
+    /// Whether there's nothing but whitespace and commas around the arguments,
+    /// so the call can be laid out again without losing comments.
+    fn has_only_separators(&self, text: &str) -> bool {
+        self.gaps()
+            .into_iter()
+            .all(|gap| text[gap].chars().all(|c| c.is_whitespace() || c == ','))
+    }
+
+    /// The text around the arguments: before the first one, between every two
+    /// of them, and after the last one.
+    fn gaps(&self) -> Vec<Range<usize>> {
+        let mut gap_start = self.loc.body_range.start;
+        let mut gaps = Vec::new();
+        for range in self.argument_ranges() {
+            gaps.push(gap_start..range.start);
+            gap_start = range.end;
+        }
+        gaps.push(gap_start..self.loc.body_range.end);
+        gaps
+    }
+}
+
+/// A literal appended to the call, as a `key => value` pair for keyed expects.
+fn format_new(indent: &str, style: &SourceStyle, literal: &Literal) -> String {
+    let format = |s: &str| format_patch(indent, StrLitKind::for_new(s, style.literals), s);
+    match &literal.key {
+        Some(key) => format!("{} => {}", format(key), format(&literal.value)),
+        None => format(&literal.value),
+    }
+}
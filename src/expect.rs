@@ -1,4 +1,4 @@
-use crate::runtime::Runtime;
+use crate::{locator, runtime::Runtime, scope};
 use std::ops::Range;
 
 /// Self-updating string literal.
@@ -7,13 +7,42 @@ pub struct Expect<const N: usize> {
     #[doc(hidden)]
     pub file_position: FilePosition,
     #[doc(hidden)]
-    pub raw_actual: &'static str,
+    pub raw_actual: Option<&'static str>,
     #[doc(hidden)]
     pub expected: [&'static str; N],
     #[doc(hidden)]
     pub raw_expected: [&'static str; N],
+    /// The key of every expected literal, for `expect!(key => actual, "key" => "expected")`
+    #[doc(hidden)]
+    pub keys: Option<[&'static str; N]>,
     #[doc(hidden)]
     pub assertion_index: usize,
+    #[doc(hidden)]
+    pub spans: Option<ExpectSpans<N>>,
+}
+
+/// Source spans captured at compile time by the `proc-macro` front end.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct ExpectSpans<const N: usize> {
+    #[doc(hidden)]
+    pub invocation: SourceSpan,
+    #[doc(hidden)]
+    pub actual: Option<SourceSpan>,
+    #[doc(hidden)]
+    pub expected: [SourceSpan; N],
+}
+
+/// One-indexed lines and columns, like `line!()` and `column!()`. The end is exclusive.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct SourceSpan {
+    #[doc(hidden)]
+    pub start_line: u32,
+    #[doc(hidden)]
+    pub start_column: u32,
+    #[doc(hidden)]
+    pub end_line: u32,
+    #[doc(hidden)]
+    pub end_column: u32,
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -33,44 +62,47 @@ impl std::fmt::Display for FilePosition {
 }
 
 impl<const N: usize> Expect<N> {
-    fn trimmed(&self, text: &str) -> String {
+    /// The expected literal at `index`, without the indentation it shares
+    /// with the code around it.
+    fn trimmed(&self, index: usize) -> String {
+        let text = self.expected[index];
+        // Only real CRs come from a file with CRLF line endings, escaped ones
+        // are part of the value
+        let text = &match self.raw_expected[index].contains('\r') {
+            true => normalize_newlines(text),
+            false => text.to_string(),
+        };
         if text.contains('\n') {
             let text = text.strip_prefix('\n').unwrap_or(text);
-            let indent_amount = text
-                .lines()
-                .filter(|line| !line.trim().is_empty())
-                .map(|line| line.len() - line.trim_start().len())
-                .min()
-                .unwrap_or(0);
-
-            let mut trimmed = text
-                .lines()
-                .map(|line| {
-                    if line.len() < indent_amount {
-                        ""
-                    } else {
-                        &line[indent_amount..]
-                    }
-                })
+            // The spaces and tabs every line starts with, so they are never
+            // taken for one another, or for escaped control characters
+            let indent = text
+                .split('\n')
+                .filter(|line| !line.trim_matches([' ', '\t']).is_empty())
+                .map(|line| &line[..line.len() - line.trim_start_matches([' ', '\t']).len()])
+                .reduce(common_prefix)
+                .unwrap_or_default();
+
+            text.split('\n')
+                .map(|line| &line[common_prefix(indent, line).len()..])
                 .collect::<Vec<&str>>()
-                .join("\n");
-            if text.ends_with('\n') {
-                trimmed.push('\n');
-            }
-            trimmed
+                .join("\n")
         } else {
             text.to_string()
         }
     }
 
     pub fn assert_eq(&self, actual: &str) {
-        if let Some(expected) = self.expected.get(self.assertion_index) {
-            let expected = self.trimmed(expected);
+        if N > 1 {
+            Runtime::record_call(self);
+        }
+        if self.assertion_index < N {
+            let expected = self.trimmed(self.assertion_index);
             if expected != actual {
-                Runtime::fail_expect(self, &expected, actual);
+                Runtime::fail_expect(self, None, &expected, actual);
             }
         } else {
-            Runtime::fail_expect(self, "", actual);
+            Runtime::fail_expect(self, None, "", actual);
         }
     }
 
@@ -81,133 +113,268 @@ impl<const N: usize> Expect<N> {
         let actual = format!("{:#?}", actual);
         self.assert_eq(&actual)
     }
-    pub fn find_expect_location(&self, file_contents: &str) -> ExpectLocation<N> {
-        let line_number: usize = (self.file_position.line - 1).try_into().unwrap(); // Zero-indexed
-        let column_number: usize = (self.file_position.column - 1).try_into().unwrap(); // Zero-indexed
-        let line_byte_offset = if line_number == 0 {
-            0
-        } else {
-            // Add 1 to skip the newline character
-            file_contents
-                .match_indices('\n')
-                .nth(line_number - 1)
-                .unwrap()
-                .0
-                + 1
-        };
-        let macro_byte_offset = line_byte_offset
-            + file_contents[line_byte_offset..]
-                .char_indices()
-                .skip(column_number)
-                .skip_while(|&(_, c)| c != '!') // macro location (ex: "expect" and "expect_tokens")
-                .nth(1) // !
-                .expect("Failed to locate macro")
-                .0; // extract index from (index, char)
-
-        fn find_ignore_whitespace(haystack: &str, pattern: &str) -> Option<(usize, usize)> {
-            fn validate(haystack: &str, pattern: &str) -> Option<(usize, usize)> {
-                let s = haystack.trim_start();
-                if s.starts_with(pattern) {
-                    let start_index = haystack.len() - s.len();
-                    Some((start_index, start_index + pattern.len()))
-                } else {
-                    None
-                }
+
+    /// Checks `actual` against the expected literal stored under `key`, no
+    /// matter in which order the keys come up.
+    pub fn assert_keyed_eq(&self, key: impl std::fmt::Display, actual: &str) {
+        let key = key.to_string();
+        if let Some(index) = self.key_index(&key) {
+            let expected = self.trimmed(index);
+            if expected != actual {
+                Runtime::fail_expect(self, Some(key.as_str()), &expected, actual);
             }
+        } else {
+            Runtime::fail_expect(self, Some(key.as_str()), "", actual);
+        }
+    }
+
+    pub fn assert_keyed_debug_eq<T>(&self, key: impl std::fmt::Display, actual: T)
+    where
+        T: std::fmt::Debug,
+    {
+        let actual = format!("{:#?}", actual);
+        self.assert_keyed_eq(key, &actual)
+    }
 
-            fn validate_fallback(haystack: &str, pattern: &str) -> Option<(usize, usize)> {
-                let mut haystack_iterator = haystack.chars().peekable();
-                let mut index = 0;
-                while let Some(_whitespace) = haystack_iterator.next_if(|c| c.is_whitespace()) {
-                    index += 1;
-                }
-                let start_index = index;
-                for pattern_char in pattern.chars().filter(|c| !c.is_whitespace()) {
-                    while let Some(_whitespace) = haystack_iterator.next_if(|c| c.is_whitespace()) {
-                        index += 1;
-                    }
-                    if let Some(c) = haystack_iterator.next()
-                        && c == pattern_char
-                    {
-                        index += 1;
-                    } else {
-                        return None;
-                    }
-                }
-                Some((start_index, index))
+    /// Checks that `actual` is one of the expected literals that the earlier
+    /// calls in this test didn't match already, in whatever order they come.
+    pub fn assert_unordered_eq(&self, actual: &str) {
+        let expected = (0..N).map(|index| self.trimmed(index)).collect::<Vec<_>>();
+        Runtime::record_unordered_call(self, &expected, actual);
+        if let Err(remaining) = scope::match_unordered(&self.file_position, &expected, actual) {
+            Runtime::fail_unordered(self, &remaining, actual);
+        }
+    }
+
+    pub fn assert_unordered_debug_eq<T>(&self, actual: T)
+    where
+        T: std::fmt::Debug,
+    {
+        let actual = format!("{:#?}", actual);
+        self.assert_unordered_eq(&actual)
+    }
+
+    fn key_index(&self, key: &str) -> Option<usize> {
+        self.keys.as_ref()?.iter().position(|&k| k == key)
+    }
+
+    /// The expected literal an assertion is checked against. A key that has
+    /// no literal yet gets one past the end.
+    pub(crate) fn literal_index(&self, key: Option<&str>) -> usize {
+        match key {
+            Some(key) => self.key_index(key).unwrap_or(N),
+            None => self.assertion_index,
+        }
+    }
+    pub fn find_expect_location(&self, file_contents: &str) -> Result<ExpectLocation, LocateError> {
+        if let Some(spans) = &self.spans {
+            if let Some(location) = location_from_spans(
+                file_contents,
+                &spans.invocation,
+                spans.actual.as_ref(),
+                &spans.expected,
+            ) {
+                return Ok(location);
             }
-            // First we're going to skip to the next character, to account for the parentheses
-            // then we're going to trim_start() and see if starts_with directly works
-            //
-            // Because stringify! does not return the raw source code in all
-            // situations, we must have some fallback implementation in case
-            // directly matching does not work. In this fallback case, we are
-            // going to process char by char, ignoring whitespace
-            let trimmed = haystack.trim_start();
-            // trim the left parentheses in expect!()
-            let trimmed = trimmed.get(1..)?;
-
-            let num_trimmed = haystack.len() - trimmed.len();
-            let (start, end) =
-                validate(trimmed, pattern).or_else(|| validate_fallback(trimmed, pattern))?;
-            Some((num_trimmed + start, num_trimmed + end))
         }
-        let (actual_start, actual_end) =
-            find_ignore_whitespace(&file_contents[macro_byte_offset..], self.raw_actual)
-                .unwrap_or_else(|| {
-                    panic!(
-                        "Unable to find actual: `{}` in `{}`",
-                        self.raw_actual,
-                        &file_contents[macro_byte_offset..]
-                    )
-                });
-        let actual_byte_offset = macro_byte_offset + actual_start;
-        let mut current_offset = macro_byte_offset + actual_end;
-
-        // let actual_byte_offset = macro_byte_offset
-        //     + file_contents[macro_byte_offset..]
-        //         .find(self.raw_actual)
-        //         .unwrap_or_else(|| {
-        //             panic!(
-        //                 "Unable to find actual: `{}` in `{}`",
-        //                 self.raw_actual,
-        //                 &file_contents[macro_byte_offset..]
-        //             )
-        //         });
-        // let actual_range = actual_byte_offset..(actual_byte_offset + self.raw_actual.len());
-        // let mut current_offset = actual_byte_offset + self.raw_actual.len();
-
-        let expected_ranges = self.raw_expected.map(|raw_expected| {
-            let start = current_offset
-                + file_contents[current_offset..]
-                    .find(raw_expected)
-                    .expect("Unable to find expected");
-            let end = start + raw_expected.len();
-            current_offset = end;
-            start..end
+        find_location(
+            file_contents,
+            self.file_position.line,
+            self.file_position.column,
+            self.raw_actual.is_some(),
+            N,
+        )
+    }
+}
+
+/// Finds an expect by parsing the macro invocation at `line` and `column`.
+/// Expects built with `expect_value!` have no actual expression.
+pub(crate) fn find_location(
+    file_contents: &str,
+    line: u32,
+    column: u32,
+    has_actual: bool,
+    num_expected: usize,
+) -> Result<ExpectLocation, LocateError> {
+    let call = locator::find_macro_call(file_contents, line, column)?;
+    let num_actual = usize::from(has_actual);
+    if call.arguments.len() != num_actual + num_expected {
+        return Err(LocateError::ArgumentCount {
+            expected: num_actual + num_expected,
+            found: call.arguments.len(),
+        });
+    }
+    let (actual, expected) = call.arguments.split_at(num_actual);
+    if let Some(index) = expected.iter().position(|arg| !arg.is_string_literal) {
+        return Err(LocateError::NotAStringLiteral {
+            index: num_actual + index,
         });
+    }
+    // Either every argument is a `key => value` pair or none of them is
+    let is_keyed = call.arguments.first().is_some_and(|arg| arg.is_keyed);
+    let mixed = call
+        .arguments
+        .iter()
+        .position(|arg| arg.is_keyed != is_keyed);
+    if let Some(index) = mixed {
+        return Err(LocateError::MixedKeys { index });
+    }
 
-        let start_index = actual_byte_offset;
-        let end_index = current_offset;
+    let start_index = call
+        .arguments
+        .first()
+        .map_or(call.body_range.end, |arg| arg.range.start);
+    let end_index = call
+        .arguments
+        .last()
+        .map_or(call.body_range.end, |arg| arg.range.end);
 
-        let line_indent = file_contents[line_byte_offset..]
-            .chars()
-            .take_while(|&c| c == ' ')
-            .count();
+    let key_ranges = expected
+        .iter()
+        .filter(|arg| arg.is_keyed)
+        .map(|arg| arg.range.start..arg.value_range.start)
+        .collect();
 
-        ExpectLocation {
-            line_indent,
-            expected_ranges,
-            start_index,
-            end_index,
-        }
+    Ok(ExpectLocation {
+        line_indent: line_indent(file_contents, call.range.start),
+        actual_range: actual.first().map(|arg| arg.value_range.clone()),
+        expected_ranges: expected.iter().map(|arg| arg.value_range.clone()).collect(),
+        key_ranges,
+        start_index,
+        end_index,
+        body_range: call.body_range,
+    })
+}
+
+fn location_from_spans(
+    file_contents: &str,
+    invocation: &SourceSpan,
+    actual: Option<&SourceSpan>,
+    expected: &[SourceSpan],
+) -> Option<ExpectLocation> {
+    let to_range = |span: &SourceSpan| {
+        let start =
+            locator::line_column_to_offset(file_contents, span.start_line, span.start_column)?;
+        let end = locator::line_column_to_offset(file_contents, span.end_line, span.end_column)?;
+        (start <= end).then_some(start..end)
+    };
+    let invocation = to_range(invocation)?;
+    let actual_range = match actual {
+        Some(span) => Some(to_range(span)?),
+        None => None,
+    };
+    let expected_ranges = expected.iter().map(to_range).collect::<Option<Vec<_>>>()?;
+
+    // Tokens passed in through another macro carry spans from somewhere
+    // else, so only trust spans that point at literals inside this invocation
+    let all_literals = expected_ranges.iter().all(|range| {
+        invocation.contains(&range.start)
+            && syn::parse_str::<syn::LitStr>(&file_contents[range.clone()]).is_ok()
+    });
+    if !all_literals
+        || !actual_range
+            .iter()
+            .all(|range| invocation.contains(&range.start))
+    {
+        return None;
     }
+
+    // The invocation ends with a single character closing delimiter, and the
+    // opening one is the first thing after the `!`
+    let body_end = invocation.end - 1;
+    let after_bang = invocation.start + file_contents[invocation.clone()].find('!')? + 1;
+    let body_start = file_contents.len() - file_contents[after_bang..].trim_start().len() + 1;
+    let start_index = actual_range
+        .iter()
+        .chain(expected_ranges.first())
+        .next()
+        .map_or(body_end, |range| range.start);
+    let end_index = expected_ranges
+        .last()
+        .or(actual_range.as_ref())
+        .map_or(body_end, |range| range.end);
+    Some(ExpectLocation {
+        line_indent: line_indent(file_contents, invocation.start),
+        actual_range,
+        expected_ranges,
+        key_ranges: Vec::new(),
+        start_index,
+        end_index,
+        body_range: body_start..body_end,
+    })
+}
+
+/// The literals of a file with CRLF line endings are compared with plain
+/// newlines, like rustc reads them.
+fn normalize_newlines(text: &str) -> String {
+    text.replace("\r\n", "\n")
+}
+
+fn common_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let len = a
+        .char_indices()
+        .zip(b.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(a.len().min(b.len()), |((index, _), _)| index);
+    &a[..len]
+}
+
+/// The leading spaces and tabs of the line containing `offset`.
+fn line_indent(file_contents: &str, offset: usize) -> String {
+    let line_byte_offset = file_contents[..offset]
+        .rfind('\n')
+        .map_or(0, |index| index + 1);
+    file_contents[line_byte_offset..]
+        .chars()
+        .take_while(|&c| c == ' ' || c == '\t')
+        .collect()
 }
 
 #[derive(Debug)]
-pub struct ExpectLocation<const N: usize> {
-    pub line_indent: usize,
-    pub expected_ranges: [Range<usize>; N],
+pub struct ExpectLocation {
+    /// The indentation of the line the macro call starts on
+    pub line_indent: String,
+    pub actual_range: Option<Range<usize>>,
+    pub expected_ranges: Vec<Range<usize>>,
+    /// From the start of every key to the start of its value, for `key => value` pairs
+    pub key_ranges: Vec<Range<usize>>,
     pub start_index: usize,
     pub end_index: usize,
+    /// Everything between the delimiters of the macro call
+    pub body_range: Range<usize>,
+}
+
+#[derive(Debug)]
+pub enum LocateError {
+    Tokenize(String),
+    MacroNotFound { line: u32, column: u32 },
+    Parse(String),
+    ArgumentCount { expected: usize, found: usize },
+    NotAStringLiteral { index: usize },
+    MixedKeys { index: usize },
+}
+
+impl std::fmt::Display for LocateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tokenize(err) => write!(f, "unable to tokenize source file: {}", err),
+            Self::MacroNotFound { line, column } => {
+                write!(f, "no macro invocation starts at {}:{}", line, column)
+            }
+            Self::Parse(err) => write!(f, "unable to parse macro arguments: {}", err),
+            Self::ArgumentCount { expected, found } => write!(
+                f,
+                "expected {} macro arguments but found {}",
+                expected, found
+            ),
+            Self::NotAStringLiteral { index } => {
+                write!(f, "macro argument {} is not a string literal", index)
+            }
+            Self::MixedKeys { index } => write!(
+                f,
+                "macro argument {} is keyed differently from the first argument",
+                index
+            ),
+        }
+    }
 }
@@ -0,0 +1,64 @@
+use std::{fmt::Display, sync::Mutex};
+
+use crate::expect::Expect;
+
+/// Collects lines from any number of callbacks and threads, then checks all
+/// of them at once against a single expected literal, when it's finished or
+/// dropped. Unlike an `expect!` with several expected literals, a callback
+/// that never runs shows up in the diff.
+///
+/// ```ignore
+/// let log = ExpectLog::new(expect_value!());
+/// some_complicated_io_func(|status_value| log.push(status_value));
+/// log.finish();
+/// ```
+pub struct ExpectLog<const N: usize> {
+    expect: Option<Expect<N>>,
+    lines: Mutex<Vec<String>>,
+}
+
+impl<const N: usize> ExpectLog<N> {
+    pub fn new(expect: Expect<N>) -> ExpectLog<N> {
+        ExpectLog {
+            expect: Some(expect),
+            lines: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn push(&self, line: impl Display) {
+        self.lines
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(line.to_string());
+    }
+
+    /// Checks the log right away instead of when it's dropped.
+    pub fn finish(mut self) {
+        self.check();
+    }
+
+    fn check(&mut self) {
+        let Some(expect) = self.expect.take() else {
+            return;
+        };
+        let lines = self
+            .lines
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut log = String::new();
+        for line in lines.iter() {
+            log.push_str(line);
+            log.push('\n');
+        }
+        expect.assert_eq(&log);
+    }
+}
+
+impl<const N: usize> Drop for ExpectLog<N> {
+    fn drop(&mut self) {
+        // A failing test already failed, and the log is likely incomplete
+        if !std::thread::panicking() {
+            self.check();
+        }
+    }
+}
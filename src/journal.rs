@@ -0,0 +1,290 @@
+// Coordination between test processes that update the same source file, such
+// as the one-process-per-test model of cargo-nextest.
+//
+// Every process computes its patches against the file as it was when the test
+// binary was built, since that's what `line!()` and `column!()` refer to. The
+// journal keeps that base text along with every edit written since, so a
+// process can tell where the file came from and rebase its own edits onto the
+// current contents. All journal access happens while holding the file's lock.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::runtime::{content_hash, expect_tests_dir};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    /// Range of the text before this edit
+    pub range: Range<usize>,
+    pub inserted_len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub from_hash: u64,
+    pub to_hash: u64,
+    /// In order of their position in the text
+    pub edits: Vec<Edit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Journal {
+    pub created: Duration,
+    pub base_text: String,
+    pub entries: Vec<JournalEntry>,
+}
+
+/// Takes an exclusive advisory lock for `source`, released when the returned file is dropped.
+pub fn lock(source: &Path) -> io::Result<File> {
+    let path = sidecar_path(source, "locks", "lock");
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    file.lock()?;
+    Ok(file)
+}
+
+pub fn sidecar_path(source: &Path, dir: &str, extension: &str) -> PathBuf {
+    let hash = content_hash(&source.to_string_lossy());
+    expect_tests_dir()
+        .join(dir)
+        .join(format!("{:016x}.{}", hash, extension))
+}
+
+/// When the running test binary was built, as time since the Unix epoch.
+pub fn build_time() -> io::Result<Duration> {
+    let modified = std::env::current_exe()?.metadata()?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+impl Journal {
+    /// Loads the journal for `source`, ignoring journals that were started
+    /// before this test binary was built since their base text is outdated.
+    pub fn load(source: &Path) -> io::Result<Option<Journal>> {
+        let path = sidecar_path(source, "journal", "journal");
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let journal = Journal::parse(&text).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed journal for {}", source.display()),
+            )
+        })?;
+        match build_time() {
+            Ok(built) if journal.created >= built => Ok(Some(journal)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Starts a new journal for `source`, replacing any previous one.
+    pub fn start(source: &Path, base_text: &str) -> io::Result<Journal> {
+        let journal = Journal {
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            base_text: base_text.to_string(),
+            entries: Vec::new(),
+        };
+        let path = sidecar_path(source, "journal", "journal");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, journal.serialize())?;
+        Ok(journal)
+    }
+
+    pub fn append(source: &Path, entry: &JournalEntry) -> io::Result<()> {
+        let path = sidecar_path(source, "journal", "journal");
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        file.write_all(serialize_entry(entry).as_bytes())
+    }
+
+    pub fn base_hash(&self) -> u64 {
+        content_hash(&self.base_text)
+    }
+
+    /// The entries leading from the text with `from_hash` to the text with `to_hash`.
+    pub fn entries_between(&self, from_hash: u64, to_hash: u64) -> Option<&[JournalEntry]> {
+        if from_hash == to_hash {
+            return Some(&[]);
+        }
+        let start = self
+            .entries
+            .iter()
+            .position(|entry| entry.from_hash == from_hash)?;
+        let end = start
+            + self.entries[start..]
+                .iter()
+                .position(|entry| entry.to_hash == to_hash)?;
+        let entries = &self.entries[start..=end];
+        let is_chain = entries
+            .windows(2)
+            .all(|pair| pair[0].to_hash == pair[1].from_hash);
+        is_chain.then_some(entries)
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut buf = format!(
+            "created {} {}\nbase {}\n{}\n",
+            self.created.as_secs(),
+            self.created.subsec_nanos(),
+            self.base_text.len(),
+            self.base_text
+        );
+        for entry in &self.entries {
+            buf.push_str(&serialize_entry(entry));
+        }
+        buf
+    }
+
+    pub fn parse(text: &str) -> Option<Journal> {
+        let (created, rest) = text.strip_prefix("created ")?.split_once('\n')?;
+        let (secs, nanos) = created.split_once(' ')?;
+        let created = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+        let (base_len, rest) = rest.strip_prefix("base ")?.split_once('\n')?;
+        let base_len = base_len.parse::<usize>().ok()?;
+        let base_text = rest.get(..base_len)?.to_string();
+        let mut lines = rest[base_len..].strip_prefix('\n')?.lines();
+        let mut entries = Vec::new();
+        while let Some(header) = lines.next() {
+            let mut header = header.strip_prefix("edit ")?.split(' ');
+            let from_hash = header.next()?.parse().ok()?;
+            let to_hash = header.next()?.parse().ok()?;
+            let count = header.next()?.parse::<usize>().ok()?;
+            let edits = (0..count)
+                .map(|_| {
+                    let mut numbers = lines.next()?.split(' ').map(|n| n.parse::<usize>().ok());
+                    let start = numbers.next()??;
+                    let end = numbers.next()??;
+                    let inserted_len = numbers.next()??;
+                    Some(Edit {
+                        range: start..end,
+                        inserted_len,
+                    })
+                })
+                .collect::<Option<Vec<_>>>()?;
+            entries.push(JournalEntry {
+                from_hash,
+                to_hash,
+                edits,
+            });
+        }
+        Some(Journal {
+            created,
+            base_text,
+            entries,
+        })
+    }
+}
+
+fn serialize_entry(entry: &JournalEntry) -> String {
+    let mut buf = format!(
+        "edit {} {} {}\n",
+        entry.from_hash,
+        entry.to_hash,
+        entry.edits.len()
+    );
+    for edit in &entry.edits {
+        buf.push_str(&format!(
+            "{} {} {}\n",
+            edit.range.start, edit.range.end, edit.inserted_len
+        ));
+    }
+    buf
+}
+
+/// Moves `range` through `edits` made by someone else to the same text.
+/// Insertions at the same offset as ours are placed before ours. Returns
+/// `None` if one of the edits overlaps `range`.
+pub fn rebase_range(range: &Range<usize>, edits: &[Edit]) -> Option<Range<usize>> {
+    let mut shift = 0isize;
+    for edit in edits {
+        if edit.range.end <= range.start {
+            shift += edit.inserted_len as isize - edit.range.len() as isize;
+        } else if edit.range.start < range.end {
+            return None;
+        }
+    }
+    let start = (range.start as isize + shift) as usize;
+    let end = (range.end as isize + shift) as usize;
+    Some(start..end)
+}
+
+/// What became of an edit of ours after other edits were made to the text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rebased {
+    Moved(Range<usize>),
+    /// Someone already made the very same edit, like a previous run of the
+    /// same test binary
+    AlreadyMade,
+    Conflict,
+}
+
+/// Moves our edit of `range` to `inserted` through `entries`, which lead to
+/// `current_text`.
+pub fn rebase_edit(
+    range: &Range<usize>,
+    inserted: &str,
+    entries: &[JournalEntry],
+    current_text: &str,
+) -> Rebased {
+    let mut range = range.clone();
+    for (i, entry) in entries.iter().enumerate() {
+        let same = entry
+            .edits
+            .iter()
+            .position(|edit| edit.range == range && edit.inserted_len == inserted.len());
+        if let Some(same) = same {
+            let made = inserted_range(&entry.edits, same);
+            let made = entries[i + 1..]
+                .iter()
+                .try_fold(made, |range, entry| rebase_range(&range, &entry.edits));
+            return match made.and_then(|made| current_text.get(made)) {
+                Some(text) if text == inserted => Rebased::AlreadyMade,
+                _ => Rebased::Conflict,
+            };
+        }
+        match rebase_range(&range, &entry.edits) {
+            Some(rebased) => range = rebased,
+            None => return Rebased::Conflict,
+        }
+    }
+    Rebased::Moved(range)
+}
+
+/// The range that `edits[index]` inserted into, in the text after `edits`,
+/// which are in order.
+fn inserted_range(edits: &[Edit], index: usize) -> Range<usize> {
+    let shift: isize = edits[..index]
+        .iter()
+        .map(|edit| edit.inserted_len as isize - edit.range.len() as isize)
+        .sum();
+    let start = (edits[index].range.start as isize + shift) as usize;
+    start..start + edits[index].inserted_len
+}
+
+/// Finds where `region` of `original` ended up in `current`, for when the file
+/// was modified by something that didn't write to the journal. Identical
+/// regions are matched up in order, so this gives up if their number changed.
+pub fn relocate_region(original: &str, region: &Range<usize>, current: &str) -> Option<usize> {
+    let needle = &original[region.clone()];
+    let occurrence = original
+        .match_indices(needle)
+        .position(|(start, _)| start == region.start)?;
+    if original.matches(needle).count() != current.matches(needle).count() {
+        return None;
+    }
+    current
+        .match_indices(needle)
+        .nth(occurrence)
+        .map(|(start, _)| start)
+}
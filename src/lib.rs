@@ -1,12 +1,25 @@
-#![feature(let_chains)]
 // based on: https://github.com/rust-analyzer/expect-test/blob/master/src/lib.rs
 
+mod atomic_write;
+mod call_edit;
 pub mod expect;
+pub mod expect_log;
 #[cfg(feature = "expect-tokens")]
 pub mod expect_tokens;
+mod journal;
+mod locator;
+mod patch_file;
 mod patchwork;
+mod pending;
+#[doc(hidden)]
+pub mod review;
 mod runtime;
+pub mod scope;
+mod source_style;
 mod str_lit_kind;
+mod summary;
+mod unified_diff;
+mod update_mode;
 
 #[cfg(test)]
 mod tests;
@@ -15,117 +28,340 @@ mod tests;
 #[cfg(test)]
 mod expect_tokens_tests;
 
+// Lets code generated by the proc macros, which refers to `::expect_tests`,
+// compile inside this crate as well
+extern crate self as expect_tests;
+
+#[cfg(feature = "proc-macro")]
+pub use expect_tests_macros::{expect, expect_unordered, expect_value};
+
+#[cfg(feature = "test-attribute")]
+pub use expect_tests_macros::test;
+
+#[cfg(all(feature = "expect-tokens", feature = "proc-macro"))]
+pub use expect_tests_macros::expect_tokens;
+
+#[cfg(not(feature = "proc-macro"))]
 #[macro_export]
 macro_rules! expect {
-    ($actual:literal) => {
+    ($actual:literal $(,)?) => {
         {
-            static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
-            let index = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
             $crate::expect::Expect {
-                file_position: $crate::expect::FilePosition {
-                    file: file!(),
-                    line: line!(),
-                    column: column!(),
-                },
-                raw_actual: stringify!($actual),
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
                 expected: [],
                 raw_expected: [],
-                assertion_index: index,
+                keys: None,
+                spans: None,
             }
             .assert_eq($actual)
         }
     };
-    ($actual:expr) => {
+    ($actual:expr $(,)?) => {
         {
-            static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
-            let index = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
             $crate::expect::Expect {
-                file_position: $crate::expect::FilePosition {
-                    file: file!(),
-                    line: line!(),
-                    column: column!(),
-                },
-                raw_actual: stringify!($actual),
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
                 expected: [],
                 raw_expected: [],
-                assertion_index: index,
+                keys: None,
+                spans: None,
             }
             .assert_debug_eq($actual)
         }
     };
-    ($actual:literal, $($expected:literal),*) => {
+    ($actual:literal, $($expected:literal),* $(,)?) => {
         {
-            static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
-            let index = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
             $crate::expect::Expect {
-                file_position: $crate::expect::FilePosition {
-                    file: file!(),
-                    line: line!(),
-                    column: column!(),
-                },
-                raw_actual: stringify!($actual),
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
                 expected: [$($expected),*],
                 raw_expected: [$(stringify!($expected)),*],
-                assertion_index: index,
+                keys: None,
+                spans: None,
             }
             .assert_eq($actual)
         }
     };
-    ($actual:expr, $($expected:literal),*) => {
+    ($actual:expr, $($expected:literal),* $(,)?) => {
         {
-            static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
-            let index = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
             $crate::expect::Expect {
-                file_position: $crate::expect::FilePosition {
-                    file: file!(),
-                    line: line!(),
-                    column: column!(),
-                },
-                raw_actual: stringify!($actual),
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
                 expected: [$($expected),*],
                 raw_expected: [$(stringify!($expected)),*],
-                assertion_index: index,
+                keys: None,
+                spans: None,
             }
             .assert_debug_eq($actual)
         }
     };
+    ($key:expr => $actual:literal $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
+                expected: [],
+                raw_expected: [],
+                keys: Some([]),
+                spans: None,
+            }
+            .assert_keyed_eq($key, $actual)
+        }
+    };
+    ($key:expr => $actual:expr $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
+                expected: [],
+                raw_expected: [],
+                keys: Some([]),
+                spans: None,
+            }
+            .assert_keyed_debug_eq($key, $actual)
+        }
+    };
+    ($key:expr => $actual:literal, $($expected_key:literal => $expected:literal),* $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
+                expected: [$($expected),*],
+                raw_expected: [$(stringify!($expected)),*],
+                keys: Some([$($expected_key),*]),
+                spans: None,
+            }
+            .assert_keyed_eq($key, $actual)
+        }
+    };
+    ($key:expr => $actual:expr, $($expected_key:literal => $expected:literal),* $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
+                expected: [$($expected),*],
+                raw_expected: [$(stringify!($expected)),*],
+                keys: Some([$($expected_key),*]),
+                spans: None,
+            }
+            .assert_keyed_debug_eq($key, $actual)
+        }
+    };
 }
 
-#[cfg(feature = "expect-tokens")]
+/// Same as `expect!` with several expected literals, except that the calls
+/// may match them in any order. Updates write the literals back sorted.
+#[cfg(not(feature = "proc-macro"))]
+#[macro_export]
+macro_rules! expect_unordered {
+    ($actual:literal $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
+                expected: [],
+                raw_expected: [],
+                keys: None,
+                spans: None,
+            }
+            .assert_unordered_eq($actual)
+        }
+    };
+    ($actual:expr $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
+                expected: [],
+                raw_expected: [],
+                keys: None,
+                spans: None,
+            }
+            .assert_unordered_debug_eq($actual)
+        }
+    };
+    ($actual:literal, $($expected:literal),* $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
+                expected: [$($expected),*],
+                raw_expected: [$(stringify!($expected)),*],
+                keys: None,
+                spans: None,
+            }
+            .assert_unordered_eq($actual)
+        }
+    };
+    ($actual:expr, $($expected:literal),* $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
+                expected: [$($expected),*],
+                raw_expected: [$(stringify!($expected)),*],
+                keys: None,
+                spans: None,
+            }
+            .assert_unordered_debug_eq($actual)
+        }
+    };
+}
+
+/// Builds an [`Expect`](crate::expect::Expect) without asserting anything, so
+/// it can be handed to a check-helper that calls `assert_eq`/`assert_debug_eq`
+/// later on. Updates are still written to the literals at this call site.
+#[cfg(not(feature = "proc-macro"))]
+#[macro_export]
+macro_rules! expect_value {
+    ($($expected:literal),* $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: None,
+                expected: [$($expected),*],
+                raw_expected: [$(stringify!($expected)),*],
+                keys: None,
+                spans: None,
+            }
+        }
+    };
+    ($($expected_key:literal => $expected:literal),+ $(,)?) => {
+        {
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
+            $crate::expect::Expect {
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: None,
+                expected: [$($expected),*],
+                raw_expected: [$(stringify!($expected)),*],
+                keys: Some([$($expected_key),*]),
+                spans: None,
+            }
+        }
+    };
+}
+
+#[cfg(all(feature = "expect-tokens", not(feature = "proc-macro")))]
 #[macro_export]
 macro_rules! expect_tokens {
-    ($actual:expr) => {
+    ($actual:expr $(,)?) => {
         {
-            static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
-            let index = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
             $crate::expect::Expect {
-                file_position: $crate::expect::FilePosition {
-                    file: file!(),
-                    line: line!(),
-                    column: column!(),
-                },
-                raw_actual: stringify!($actual),
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
                 expected: [],
                 raw_expected: [],
-                assertion_index: index,
+                keys: None,
+                spans: None,
             }
             .assert_eq(&$crate::expect_tokens::ExpectTokens::convert($actual))
         }
     };
-    ($actual:expr, $($expected:literal),*) => {
+    ($actual:expr, $($expected:literal),* $(,)?) => {
         {
-            static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
-            let index = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let file_position = $crate::expect::FilePosition {
+                file: file!(),
+                line: line!(),
+                column: column!(),
+            };
             $crate::expect::Expect {
-                file_position: $crate::expect::FilePosition {
-                    file: file!(),
-                    line: line!(),
-                    column: column!(),
-                },
-                raw_actual: stringify!($actual),
+                assertion_index: $crate::scope::next_assertion_index(&file_position),
+                file_position,
+                raw_actual: Some(stringify!($actual)),
                 expected: [$($expected),*],
                 raw_expected: [$(stringify!($expected)),*],
-                assertion_index: index,
+                keys: None,
+                spans: None,
             }
             .assert_eq(&$crate::expect_tokens::ExpectTokens::convert($actual))
         }
@@ -0,0 +1,165 @@
+use std::ops::Range;
+
+use proc_macro2::{TokenStream, TokenTree};
+use syn::{
+    parse::{ParseStream, Parser},
+    punctuated::Punctuated,
+    spanned::Spanned,
+    Expr, ExprLit, Lit, Token,
+};
+
+use crate::expect::LocateError;
+
+/// A macro invocation in a source file. All ranges are byte offsets into that file.
+#[derive(Debug)]
+pub struct MacroCall {
+    /// From the start of the macro path to the closing delimiter (inclusive)
+    pub range: Range<usize>,
+    /// Everything between the delimiters
+    pub body_range: Range<usize>,
+    pub arguments: Vec<MacroArgument>,
+}
+
+#[derive(Debug)]
+pub struct MacroArgument {
+    /// The whole argument, including the key of a `key => value` pair
+    pub range: Range<usize>,
+    /// The value of a `key => value` pair, or else the whole argument
+    pub value_range: Range<usize>,
+    pub is_keyed: bool,
+    /// Whether the value, and the key if there is one, are string literals
+    pub is_string_literal: bool,
+}
+
+/// Tokenizes `file_contents` and finds the macro invocation whose path starts
+/// at the given one-indexed `line` and `column` (as reported by `line!()` and
+/// `column!()`), then parses its comma separated arguments as expressions or
+/// `key => value` pairs of expressions.
+pub fn find_macro_call(
+    file_contents: &str,
+    line: u32,
+    column: u32,
+) -> Result<MacroCall, LocateError> {
+    let tokens = file_contents
+        .parse::<TokenStream>()
+        .map_err(|err| LocateError::Tokenize(err.to_string()))?;
+    let (path_start, group) = find_invocation(tokens, line as usize, column as usize)
+        .ok_or(LocateError::MacroNotFound { line, column })?;
+
+    let parse_arguments = |input: ParseStream| {
+        Punctuated::<_, Token![,]>::parse_terminated_with(input, parse_argument)
+    };
+    let pairs = parse_arguments
+        .parse2(group.stream())
+        .map_err(|err| LocateError::Parse(err.to_string()))?;
+    let arguments = pairs
+        .iter()
+        .map(|(key, value)| {
+            let value_range = value.span().byte_range();
+            MacroArgument {
+                range: key
+                    .as_ref()
+                    .map_or(value_range.start, |key| key.span().byte_range().start)
+                    ..value_range.end,
+                value_range,
+                is_keyed: key.is_some(),
+                is_string_literal: key.iter().chain([value]).all(is_string_literal),
+            }
+        })
+        .collect();
+
+    Ok(MacroCall {
+        range: path_start..group.span_close().byte_range().end,
+        body_range: group.span_open().byte_range().end..group.span_close().byte_range().start,
+        arguments,
+    })
+}
+
+fn parse_argument(input: ParseStream) -> syn::Result<(Option<Expr>, Expr)> {
+    let first = input.parse()?;
+    if input.parse::<Option<Token![=>]>>()?.is_some() {
+        Ok((Some(first), input.parse()?))
+    } else {
+        Ok((None, first))
+    }
+}
+
+fn is_string_literal(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Lit(ExprLit {
+            lit: Lit::Str(_),
+            ..
+        })
+    )
+}
+
+/// Searches for `path!(...)` (with any delimiter) whose path starts at `line`
+/// and `column`, descending into every group including the bodies of other macros.
+fn find_invocation(
+    tokens: TokenStream,
+    line: usize,
+    column: usize,
+) -> Option<(usize, proc_macro2::Group)> {
+    let tokens = tokens.into_iter().collect::<Vec<_>>();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            TokenTree::Punct(punct) if punct.as_char() == '!' && i > 0 => {
+                if let (TokenTree::Ident(_), Some(TokenTree::Group(group))) =
+                    (&tokens[i - 1], tokens.get(i + 1))
+                {
+                    let path_start = tokens[start_of_path(&tokens, i - 1)].span();
+                    let start = path_start.start();
+                    // LineColumn columns are zero-indexed while `column!()` is one-indexed
+                    if start.line == line && start.column + 1 == column {
+                        return Some((path_start.byte_range().start, group.clone()));
+                    }
+                }
+            }
+            TokenTree::Group(group) => {
+                if let Some(found) = find_invocation(group.stream(), line, column) {
+                    return Some(found);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Walks backwards from the last segment of a path like `::expect_tests::expect`
+/// and returns the index of its first token.
+fn start_of_path(tokens: &[TokenTree], last_segment: usize) -> usize {
+    let is_colon =
+        |index: usize| matches!(&tokens[index], TokenTree::Punct(punct) if punct.as_char() == ':');
+    let mut start = last_segment;
+    while start >= 2 && is_colon(start - 1) && is_colon(start - 2) {
+        if start >= 3 && matches!(tokens[start - 3], TokenTree::Ident(_)) {
+            start -= 3;
+        } else {
+            // Leading `::` of an absolute path
+            start -= 2;
+            break;
+        }
+    }
+    start
+}
+
+/// Converts a one-indexed line and column (counted in chars) into a byte
+/// offset. A column one past the end of the line points at the line ending.
+pub fn line_column_to_offset(file_contents: &str, line: u32, column: u32) -> Option<usize> {
+    let line_start = match (line as usize).checked_sub(2) {
+        None if line == 1 => 0,
+        None => return None,
+        Some(newlines) => file_contents.match_indices('\n').nth(newlines)?.0 + 1,
+    };
+    let line_text = &file_contents[line_start..];
+    let line_text = &line_text[..line_text.find('\n').unwrap_or(line_text.len())];
+    let column = (column as usize).checked_sub(1)?;
+    line_text
+        .char_indices()
+        .map(|(index, _)| index)
+        .chain(std::iter::once(line_text.len()))
+        .nth(column)
+        .map(|index| line_start + index)
+}
@@ -0,0 +1,94 @@
+// `UPDATE_EXPECT=patch` leaves the source files alone, for read-only or
+// sandboxed checkouts. Updates are written to a shadow copy of every source
+// file instead, through the same journal as regular updates, and the patch
+// file is regenerated from all shadows whenever a test process exits.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    journal::{self, sidecar_path, Journal},
+    runtime::{content_hash, expect_tests_dir, to_abs_ws_path, workspace_root},
+    unified_diff::unified_diff,
+};
+
+/// `EXPECT_PATCH_FILE` relative to the workspace root, or
+/// `target/expect-tests/updates.patch` by default.
+pub fn path() -> PathBuf {
+    match std::env::var_os("EXPECT_PATCH_FILE") {
+        Some(path) => to_abs_ws_path(Path::new(&path)),
+        None => expect_tests_dir().join("updates.patch"),
+    }
+}
+
+pub fn shadow_path(source: &Path) -> PathBuf {
+    sidecar_path(source, "shadow", "rs")
+}
+
+/// The journal of `shadow`, if the shadow is based on `source_text`. A shadow
+/// left over from an earlier run is based on an older source once its patch
+/// was applied or the source was edited, and has to be started over.
+pub fn shadow_journal(source_text: &str, shadow: &Path) -> io::Result<Option<Journal>> {
+    let journal = Journal::load(shadow)?;
+    Ok(journal.filter(|journal| journal.base_hash() == content_hash(source_text)))
+}
+
+/// Starts the shadow of `source` as a copy of it, next to a `.source` file
+/// recording where it came from. Replaces any previous shadow and its journal.
+pub fn create_shadow(source: &Path, shadow: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(shadow.parent().unwrap())?;
+    match std::fs::remove_file(sidecar_path(shadow, "journal", "journal")) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    std::fs::write(
+        shadow.with_extension("source"),
+        source.to_string_lossy().as_bytes(),
+    )?;
+    std::fs::copy(source, shadow)?;
+    Ok(())
+}
+
+/// Regenerates the patch file from every shadow that is still based on the
+/// current contents of its source file, returning the path of the patch file.
+pub fn write() -> io::Result<PathBuf> {
+    let path = path();
+    let _lock = journal::lock(&path)?;
+    let mut diffs = BTreeMap::new();
+    for entry in std::fs::read_dir(expect_tests_dir().join("shadow"))? {
+        let source_file = entry?.path();
+        if source_file
+            .extension()
+            .is_none_or(|extension| extension != "source")
+        {
+            continue;
+        }
+        let source = PathBuf::from(std::fs::read_to_string(&source_file)?);
+        let shadow = source_file.with_extension("rs");
+        let _lock = journal::lock(&source)?;
+        let Some(journal) = Journal::load(&shadow)? else {
+            continue;
+        };
+        let Ok(source_text) = std::fs::read_to_string(&source) else {
+            continue;
+        };
+        // The patch was already applied, or the source changed some other way
+        if journal.base_hash() != content_hash(&source_text) {
+            continue;
+        }
+        let name = source
+            .strip_prefix(workspace_root())
+            .unwrap_or(&source)
+            .to_string_lossy()
+            .into_owned();
+        let shadow_text = std::fs::read_to_string(&shadow)?;
+        let diff = unified_diff(&name, &source_text, &shadow_text);
+        diffs.insert(name, diff);
+    }
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, diffs.into_values().collect::<String>())?;
+    Ok(path)
+}
@@ -57,9 +57,25 @@ impl Patchwork {
     pub fn text(&self) -> &str {
         self.text.as_ref()
     }
+
+    /// Every patch as a range of the original text and the text that replaced it, in order.
+    pub fn edits(&self) -> Vec<(Range<usize>, &str)> {
+        let mut offset = 0i32;
+        self.patches
+            .iter()
+            .map(|patch| {
+                let start = (patch.deletion_range.start as i32 + offset) as usize;
+                let inserted = &self.text[start..start + patch.insertion_size];
+                offset += patch.insertion_size as i32 - patch.deletion_range.len() as i32;
+                (patch.deletion_range.clone(), inserted)
+            })
+            .collect()
+    }
 }
 
+/// Orders patches at the same offset.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(dead_code)] // Calls are laid out as a whole now, so nothing else needs the extremes
 pub enum PatchOrdering {
     BeforeOtherPatches,
     Normal,
@@ -0,0 +1,159 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::runtime::{content_hash, expect_tests_dir};
+
+/// A mismatch recorded by `UPDATE_EXPECT=review`, waiting for `cargo expect review`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingSnapshot {
+    pub file: PathBuf,
+    /// Hash of the source file when the snapshot was recorded, so stale
+    /// snapshots aren't applied to a file that has since changed
+    pub file_hash: u64,
+    pub line: u32,
+    pub column: u32,
+    pub has_actual: bool,
+    pub num_expected: usize,
+    pub assertion_index: usize,
+    /// The key of a keyed expect
+    pub key: Option<String>,
+    pub test_name: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl PendingSnapshot {
+    pub fn dir() -> PathBuf {
+        expect_tests_dir().join("pending")
+    }
+
+    /// Saves to a file named after the assertion, so re-running a test replaces
+    /// its previous pending snapshot instead of adding another one. Returns
+    /// the path of that file.
+    pub fn save(&self) -> io::Result<PathBuf> {
+        let dir = PendingSnapshot::dir();
+        std::fs::create_dir_all(&dir)?;
+        let assertion = format!(
+            "{}:{}:{}:{}:{:?}",
+            self.file.display(),
+            self.line,
+            self.column,
+            self.assertion_index,
+            self.key
+        );
+        let path = dir.join(format!("{:016x}.pending", content_hash(&assertion)));
+        std::fs::write(&path, self.serialize())?;
+        Ok(path)
+    }
+
+    /// Loads every pending snapshot along with the path it was loaded from.
+    pub fn load_all() -> io::Result<Vec<(PathBuf, PendingSnapshot)>> {
+        let dir = PendingSnapshot::dir();
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "pending")
+            {
+                let text = std::fs::read_to_string(&path)?;
+                let snapshot = PendingSnapshot::parse(&text).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Malformed pending snapshot: {}", path.display()),
+                    )
+                })?;
+                snapshots.push((path, snapshot));
+            }
+        }
+        snapshots.sort_by(|(_, a), (_, b)| {
+            (&a.file, a.line, a.column, a.assertion_index).cmp(&(
+                &b.file,
+                b.line,
+                b.column,
+                b.assertion_index,
+            ))
+        });
+        Ok(snapshots)
+    }
+
+    /// One `key value` line per field, except for the snapshot contents which
+    /// are prefixed with their length in bytes since they may contain anything.
+    /// So is the key, which is left out for expects without keys.
+    pub fn serialize(&self) -> String {
+        let key = match &self.key {
+            Some(key) => format!("key {}\n{}\n", key.len(), key),
+            None => String::new(),
+        };
+        format!(
+            "file {}\nfile_hash {}\nline {}\ncolumn {}\nhas_actual {}\nnum_expected {}\nassertion_index {}\n{}test_name {}\nold {}\n{}\nnew {}\n{}\n",
+            self.file.display(),
+            self.file_hash,
+            self.line,
+            self.column,
+            self.has_actual,
+            self.num_expected,
+            self.assertion_index,
+            key,
+            self.test_name,
+            self.old.len(),
+            self.old,
+            self.new.len(),
+            self.new,
+        )
+    }
+
+    pub fn parse(mut text: &str) -> Option<PendingSnapshot> {
+        let rest = &mut text;
+        let file = Path::new(take_field(rest, "file")?).to_path_buf();
+        let file_hash = take_field(rest, "file_hash")?.parse().ok()?;
+        let line = take_field(rest, "line")?.parse().ok()?;
+        let column = take_field(rest, "column")?.parse().ok()?;
+        let has_actual = take_field(rest, "has_actual")?.parse().ok()?;
+        let num_expected = take_field(rest, "num_expected")?.parse().ok()?;
+        let assertion_index = take_field(rest, "assertion_index")?.parse().ok()?;
+        let key = if rest.starts_with("key ") {
+            let key_len = take_field(rest, "key")?.parse().ok()?;
+            Some(take_contents(rest, key_len)?.to_string())
+        } else {
+            None
+        };
+        let test_name = take_field(rest, "test_name")?.to_string();
+        let old_len = take_field(rest, "old")?.parse().ok()?;
+        let old = take_contents(rest, old_len)?.to_string();
+        let new_len = take_field(rest, "new")?.parse().ok()?;
+        let new = take_contents(rest, new_len)?.to_string();
+        Some(PendingSnapshot {
+            file,
+            file_hash,
+            line,
+            column,
+            has_actual,
+            num_expected,
+            assertion_index,
+            key,
+            test_name,
+            old,
+            new,
+        })
+    }
+}
+
+/// Takes a `key value` line, returning the value.
+fn take_field<'a>(text: &mut &'a str, key: &str) -> Option<&'a str> {
+    let (line, rest) = text.split_once('\n')?;
+    *text = rest;
+    line.strip_prefix(key)?.strip_prefix(' ')
+}
+
+/// Takes `len` bytes of contents followed by a newline.
+fn take_contents<'a>(text: &mut &'a str, len: usize) -> Option<&'a str> {
+    let contents = text.get(..len)?;
+    *text = text[len..].strip_prefix('\n')?;
+    Some(contents)
+}
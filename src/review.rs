@@ -0,0 +1,201 @@
+// Implementation of `cargo expect review`, which walks through the snapshots
+// recorded by `UPDATE_EXPECT=review` and applies the accepted ones.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    atomic_write::write_atomically,
+    call_edit::CallEdit,
+    expect::find_location,
+    patchwork::Patchwork,
+    pending::PendingSnapshot,
+    runtime::{content_hash, format_chunks},
+    source_style::SourceStyle,
+};
+
+const USAGE: &str = "Usage: cargo expect review
+
+Walks through the snapshots recorded by `UPDATE_EXPECT=review cargo test`
+and writes the accepted ones into the source files.";
+
+pub fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    // `cargo expect review` runs us as `cargo-expect expect review`
+    let args = match args.first() {
+        Some(first) if first == "expect" => &args[1..],
+        _ => &args[..],
+    };
+    match args {
+        [command] if command == "review" => {
+            if let Err(err) = review() {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+enum Decision {
+    Accept,
+    Reject,
+    Skip,
+    Quit,
+}
+
+fn review() -> io::Result<()> {
+    let snapshots = PendingSnapshot::load_all()?;
+    if snapshots.is_empty() {
+        println!(
+            "No pending snapshots in {}",
+            PendingSnapshot::dir().display()
+        );
+        return Ok(());
+    }
+
+    let total = snapshots.len();
+    let mut accepted: BTreeMap<PathBuf, Vec<(PathBuf, PendingSnapshot)>> = BTreeMap::new();
+    let mut stdin = io::stdin().lock();
+    for (i, (path, snapshot)) in snapshots.into_iter().enumerate() {
+        print_snapshot(i + 1, total, &snapshot);
+        match prompt(&mut stdin)? {
+            Decision::Accept => accepted
+                .entry(snapshot.file.clone())
+                .or_default()
+                .push((path, snapshot)),
+            Decision::Reject => std::fs::remove_file(path)?,
+            Decision::Skip => {}
+            Decision::Quit => break,
+        }
+    }
+
+    for (file, snapshots) in accepted {
+        apply(&file, &snapshots)?;
+    }
+    Ok(())
+}
+
+fn print_snapshot(number: usize, total: usize, snapshot: &PendingSnapshot) {
+    println!(
+        "
+\x1b[1m[{}/{}]\x1b[0m {}:{}:{} ({})
+
+\x1b[1mOld\x1b[0m:
+----
+{}
+----
+
+\x1b[1mNew\x1b[0m:
+----
+{}
+----
+
+\x1b[1mDiff\x1b[0m:
+----
+{}
+----",
+        number,
+        total,
+        snapshot.file.display(),
+        snapshot.line,
+        snapshot.column,
+        snapshot.test_name,
+        snapshot.old,
+        snapshot.new,
+        format_chunks(dissimilar::diff(&snapshot.old, &snapshot.new)),
+    );
+}
+
+fn prompt(stdin: &mut impl BufRead) -> io::Result<Decision> {
+    loop {
+        print!("[a]ccept, [r]eject, [s]kip, [q]uit? ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(Decision::Quit);
+        }
+        match line.trim() {
+            "a" | "accept" => return Ok(Decision::Accept),
+            "r" | "reject" => return Ok(Decision::Reject),
+            "s" | "skip" => return Ok(Decision::Skip),
+            "q" | "quit" => return Ok(Decision::Quit),
+            _ => {}
+        }
+    }
+}
+
+/// Writes all accepted snapshots of one file in a single pass, since each
+/// snapshot's position refers to the file as it was when the tests ran.
+fn apply(file: &Path, snapshots: &[(PathBuf, PendingSnapshot)]) -> io::Result<()> {
+    let text = std::fs::read_to_string(file)?;
+    let hash = content_hash(&text);
+    let mut calls: BTreeMap<(u32, u32), CallEdit> = BTreeMap::new();
+    let mut applied = Vec::new();
+    // How many new keys were added to every keyed expect so far
+    let mut new_keys: HashMap<(u32, u32), usize> = HashMap::new();
+    for (path, snapshot) in snapshots {
+        if snapshot.file_hash != hash {
+            eprintln!(
+                "skipping {}:{}:{}: the file changed since the snapshot was recorded, re-run the tests",
+                file.display(),
+                snapshot.line,
+                snapshot.column
+            );
+            continue;
+        }
+        match find_location(
+            &text,
+            snapshot.line,
+            snapshot.column,
+            snapshot.has_actual,
+            snapshot.num_expected,
+        ) {
+            Ok(loc) => {
+                let mut assertion_index = snapshot.assertion_index;
+                if snapshot.key.is_some() && assertion_index >= snapshot.num_expected {
+                    let added = new_keys
+                        .entry((snapshot.line, snapshot.column))
+                        .or_default();
+                    assertion_index += *added;
+                    *added += 1;
+                }
+                calls
+                    .entry((snapshot.line, snapshot.column))
+                    .or_insert_with(|| CallEdit::new(loc))
+                    .set(assertion_index, snapshot.key.as_deref(), &snapshot.new);
+                applied.push(path);
+            }
+            Err(err) => eprintln!(
+                "skipping {}:{}:{}: {}",
+                file.display(),
+                snapshot.line,
+                snapshot.column,
+                err
+            ),
+        }
+    }
+    if !applied.is_empty() {
+        let style = SourceStyle::of_file(file, &text);
+        let mut patchwork = Patchwork::new(text.clone());
+        for call in calls.values() {
+            call.apply(&text, &style, &mut patchwork);
+        }
+        write_atomically(file, patchwork.text())?;
+        println!(
+            "\x1b[1m\x1b[92mupdated\x1b[0m: {} ({} snapshots)",
+            file.display(),
+            applied.len()
+        );
+    }
+    for path in applied {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
@@ -1,47 +1,427 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    ops::Range,
     path::{Path, PathBuf},
-    sync::Mutex,
+    sync::{Mutex, Once},
+    thread::ThreadId,
+    time::UNIX_EPOCH,
 };
 
 use once_cell::sync::{Lazy, OnceCell};
 
 use crate::{
-    expect::{Expect, FilePosition},
+    atomic_write::write_atomically,
+    call_edit::CallEdit,
+    expect::{find_location, Expect, ExpectLocation, FilePosition},
+    journal::{self, rebase_edit, relocate_region, Edit, Journal, JournalEntry, Rebased},
+    patch_file,
     patchwork::{PatchOrdering, Patchwork},
+    pending::PendingSnapshot,
+    scope::{self, Failure, FinishedTest},
+    source_style::SourceStyle,
     str_lit_kind::StrLitKind,
+    summary::{plural, Summary},
+    unified_diff::unified_diff,
+    update_mode::UpdateMode,
 };
 const HELP: &str = "
 You can update all `expect!` tests by running:
     UPDATE_EXPECT=1 cargo test
+To only fill in `expect!` tests without an expected value, run:
+    UPDATE_EXPECT=new cargo test
+To review each change before it is written, run:
+    UPDATE_EXPECT=review cargo test && cargo expect review
 To update a single test, place the cursor on `expect` token and use `run` feature of rust-analyzer.
 ";
 
 #[derive(Default)]
 pub struct Runtime {
     help_printed: bool,
-    per_file: HashMap<&'static str, FileRuntime>,
+    pub(crate) per_file: HashMap<&'static str, FileRuntime>,
+    pub(crate) failures: BTreeMap<&'static str, usize>,
+    /// The pending snapshots saved so far, which later saves for the same
+    /// assertion overwrite
+    pending: HashSet<PathBuf>,
+    sites: HashMap<FilePosition, SiteCalls>,
+    /// Expects whose unused literals already failed a test
+    pub(crate) reported: HashSet<FilePosition>,
 }
 static RT: Lazy<Mutex<Runtime>> = Lazy::new(Default::default);
+/// Threads that panicked, since a test that failed part way through didn't
+/// get to run all of its expects
+static PANICKED: Lazy<Mutex<HashSet<ThreadId>>> = Lazy::new(Default::default);
 
-fn update_expect() -> bool {
-    std::env::var("UPDATE_EXPECT").is_ok()
+/// How often an expect with several expected literals ran, to find the
+/// literals it never reaches.
+struct SiteCalls {
+    position: FilePosition,
+    has_actual: bool,
+    num_expected: usize,
+    count: usize,
+    threads: Vec<ThreadId>,
+    /// Set for `expect_unordered!`, which gets all its literals rewritten at once
+    unordered: Option<UnorderedValues>,
+}
+
+struct UnorderedValues {
+    /// The expected literals, trimmed
+    expected: Vec<String>,
+    /// The actual values by assertion index
+    actual: Vec<String>,
 }
 
 impl Runtime {
-    pub fn fail_expect<const N: usize>(expect: &Expect<N>, expected: &str, actual: &str) {
+    pub fn record_call<const N: usize>(expect: &Expect<N>) {
+        Runtime::record(expect, None);
+    }
+    pub fn record_unordered_call<const N: usize>(
+        expect: &Expect<N>,
+        expected: &[String],
+        actual: &str,
+    ) {
+        Runtime::record(expect, Some((expected, actual)));
+    }
+    fn record<const N: usize>(expect: &Expect<N>, unordered: Option<(&[String], &str)>) {
+        scope::record(&expect.file_position, N);
         let mut rt = RT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
-        if update_expect() {
-            println!("\x1b[1m\x1b[92mupdating\x1b[0m: {}", expect.file_position);
-            rt.per_file
-                .entry(expect.file_position.file)
-                .or_insert_with_key(|&filename| FileRuntime::new(filename))
-                .update(expect, actual);
+        register_exit_hook();
+        watch_panics();
+        rt.count_call(expect, unordered);
+    }
+    pub(crate) fn count_call<const N: usize>(
+        &mut self,
+        expect: &Expect<N>,
+        unordered: Option<(&[String], &str)>,
+    ) {
+        let site = self
+            .sites
+            .entry(expect.file_position.clone())
+            .or_insert_with(|| SiteCalls {
+                position: expect.file_position.clone(),
+                has_actual: expect.raw_actual.is_some(),
+                num_expected: N,
+                count: 0,
+                threads: Vec::new(),
+                unordered: None,
+            });
+        site.count = site.count.max(expect.assertion_index + 1);
+        let thread = std::thread::current().id();
+        if !site.threads.contains(&thread) {
+            site.threads.push(thread);
+        }
+        if let Some((expected, actual)) = unordered {
+            let values = site.unordered.get_or_insert_with(|| UnorderedValues {
+                expected: expected.to_vec(),
+                actual: Vec::new(),
+            });
+            let index = expect.assertion_index;
+            if values.actual.len() <= index {
+                values.actual.resize(index + 1, String::new());
+            }
+            values.actual[index] = actual.to_string();
+        }
+    }
+    pub fn fail_expect<const N: usize>(
+        expect: &Expect<N>,
+        key: Option<&str>,
+        expected: &str,
+        actual: &str,
+    ) {
+        let literal_index = expect.literal_index(key);
+        let has_expected = literal_index < N;
+        let mode = UpdateMode::from_env().filter(|mode| mode.allows_update(has_expected));
+        let mut rt = RT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        register_exit_hook();
+        match mode {
+            Some(UpdateMode::Review) => {
+                println!("\x1b[1m\x1b[93mpending\x1b[0m: {}", expect.file_position);
+                let path = to_abs_ws_path(Path::new(expect.file_position.file));
+                let file_text = std::fs::read_to_string(&path).unwrap();
+                let snapshot_path = PendingSnapshot {
+                    file: path,
+                    file_hash: content_hash(&file_text),
+                    line: expect.file_position.line,
+                    column: expect.file_position.column,
+                    has_actual: expect.raw_actual.is_some(),
+                    num_expected: N,
+                    assertion_index: literal_index,
+                    key: key.map(str::to_string),
+                    test_name: std::thread::current()
+                        .name()
+                        .unwrap_or("<unnamed>")
+                        .to_string(),
+                    old: expected.to_string(),
+                    new: actual.to_string(),
+                }
+                .save()
+                .unwrap_or_else(|err| panic!("Unable to save pending snapshot: {}", err));
+                rt.pending.insert(snapshot_path);
+            }
+            Some(mode) => {
+                let action = match mode {
+                    UpdateMode::DryRun => "would update",
+                    _ => "updating",
+                };
+                println!("\x1b[1m\x1b[92m{}\x1b[0m: {}", action, expect.file_position);
+                rt.per_file
+                    .entry(expect.file_position.file)
+                    .or_insert_with_key(|&filename| FileRuntime::new(filename, mode))
+                    .update(expect, key, actual);
+            }
+            None => {
+                *rt.failures.entry(expect.file_position.file).or_default() += 1;
+                if !scope::defer_failure(&expect.file_position, expected, actual) {
+                    rt.panic(&expect.file_position, expected, actual)
+                }
+            }
+        }
+    }
+    /// Fails an `expect_unordered!` whose actual value isn't among the
+    /// `remaining` literals. Updates are left to the end of the process,
+    /// when all the values are known.
+    pub fn fail_unordered<const N: usize>(expect: &Expect<N>, remaining: &[String], actual: &str) {
+        let mode = UpdateMode::from_env();
+        if mode == Some(UpdateMode::Review) {
+            panic!(
+                "Unable to review expect_unordered! at {}: `cargo expect review` can't update unordered expects, run with `UPDATE_EXPECT=1` instead",
+                expect.file_position
+            );
+        }
+        let mode = mode.filter(|&mode| mode.rewrites_unordered(N > 0));
+        let mut rt = RT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        register_exit_hook();
+        if mode.is_some() {
             return;
         }
-        rt.panic(&expect.file_position, expected, actual);
+        *rt.failures.entry(expect.file_position.file).or_default() += 1;
+        let expected = closest(remaining, actual);
+        if !scope::defer_failure(&expect.file_position, expected, actual) {
+            rt.panic(&expect.file_position, expected, actual)
+        }
+    }
+    /// Writes every updated file, once per file at process exit, so a test
+    /// binary that gets killed never leaves a half written source file behind.
+    /// Then sums up what happened, since the per-expect output is scattered
+    /// throughout the test output.
+    fn finish(&mut self) {
+        let mode = UpdateMode::from_env();
+        let dry_run = mode == Some(UpdateMode::DryRun);
+        let surplus = self.find_surplus(mode);
+        self.rewrite_unordered(mode);
+        let mut summary = Summary {
+            failed: self.failures.clone(),
+            surplus,
+            pending: self.pending.len(),
+            dry_run,
+            ..Default::default()
+        };
+        for (&filename, file) in &self.per_file {
+            match file.flush(dry_run) {
+                Ok(()) => {
+                    summary.updated.insert(filename, file.updated);
+                }
+                Err(err) => eprintln!(
+                    "\x1b[1m\x1b[91merror\x1b[0m: unable to update {}: {}",
+                    file.path.display(),
+                    err
+                ),
+            }
+        }
+        if mode == Some(UpdateMode::Patch) && !self.per_file.is_empty() {
+            match patch_file::write() {
+                Ok(path) => summary.patch_file = Some(path),
+                Err(err) => eprintln!(
+                    "\x1b[1m\x1b[91merror\x1b[0m: unable to write {}: {}",
+                    patch_file::path().display(),
+                    err
+                ),
+            }
+        }
+        if !summary.is_empty() {
+            eprint!("\n\x1b[1mexpect-tests summary\x1b[0m:\n{}", summary);
+        }
+        if surplus > 0 {
+            extern "C" {
+                fn _exit(status: std::os::raw::c_int) -> !;
+            }
+            // SAFETY: unlike `exit`, `_exit` may be called from an atexit
+            // handler. `std::process::exit` aborts once `main` returned.
+            unsafe { _exit(101) }
+        }
+    }
+    /// Removes the expected literals that were never reached, or reports them
+    /// as failures if we aren't updating. Returns how many expects were reported.
+    pub(crate) fn find_surplus(&mut self, mode: Option<UpdateMode>) -> usize {
+        let panicked = PANICKED
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut surplus = 0;
+        // No test ran an expect after these finished to report them
+        let finished = scope::take_finished_tests();
+        if !finished.is_empty() {
+            eprintln!(
+                "\n\x1b[1m\x1b[91merror\x1b[97m: expects did not use all of their expected literals\x1b[0m
+{}Run with `UPDATE_EXPECT=1` to remove the rest.",
+                scope::describe_finished_tests(&finished)
+            );
+            surplus += self.mark_reported(&finished);
+        }
+        let mut sites = self.sites.values().collect::<Vec<_>>();
+        sites.sort_by_key(|site| (site.position.file, site.position.line, site.position.column));
+        for site in sites {
+            if site.count >= site.num_expected
+                || self.reported.contains(&site.position)
+                || site.threads.iter().any(|thread| panicked.contains(thread))
+            {
+                continue;
+            }
+            match mode {
+                // Their literals get rewritten as a whole
+                Some(mode) if mode.removes_surplus() && site.unordered.is_some() => {}
+                Some(mode) if mode.removes_surplus() => {
+                    let file = self
+                        .per_file
+                        .entry(site.position.file)
+                        .or_insert_with_key(|&filename| FileRuntime::new(filename, mode));
+                    if let Err(err) = file.remove_surplus(site) {
+                        eprintln!(
+                            "\x1b[1m\x1b[91merror\x1b[0m: unable to remove surplus literals at {}: {}",
+                            site.position, err
+                        );
+                    }
+                }
+                _ => {
+                    eprintln!(
+                        "\n\x1b[1m\x1b[91merror\x1b[97m: expect has surplus expected literals\x1b[0m
+   \x1b[1m\x1b[34m-->\x1b[0m {}
+It ran {} but has {} expected literals. Run with `UPDATE_EXPECT=1` to remove the rest.",
+                        site.position,
+                        plural(site.count, "time"),
+                        site.num_expected
+                    );
+                    *self.failures.entry(site.position.file).or_default() += 1;
+                    surplus += 1;
+                }
+            }
+        }
+        surplus
+    }
+    /// Counts the expects of `finished` tests as failures that were reported,
+    /// returning how many there are.
+    fn mark_reported(&mut self, finished: &[FinishedTest]) -> usize {
+        let positions = finished.iter().flat_map(|finished| &finished.positions);
+        let mut count = 0;
+        for position in positions {
+            *self.failures.entry(position.file).or_default() += 1;
+            self.reported.insert(position.clone());
+            count += 1;
+        }
+        count
+    }
+    /// Replaces the literals of every `expect_unordered!` that saw different
+    /// values than it expected with the values it saw, sorted.
+    fn rewrite_unordered(&mut self, mode: Option<UpdateMode>) {
+        let panicked = PANICKED
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut sites = self
+            .sites
+            .values()
+            .filter(|site| site.unordered.is_some())
+            .collect::<Vec<_>>();
+        sites.sort_by_key(|site| (site.position.file, site.position.line, site.position.column));
+        for site in sites {
+            let (Some(mode), Some(values)) = (mode, &site.unordered) else {
+                continue;
+            };
+            if !mode.rewrites_unordered(site.num_expected > 0) {
+                continue;
+            }
+            let mut actual = values.actual.clone();
+            actual.sort();
+            let mut expected = values.expected.clone();
+            expected.sort();
+            if actual == expected || site.threads.iter().any(|thread| panicked.contains(thread)) {
+                continue;
+            }
+            let action = match mode {
+                UpdateMode::DryRun => "would update",
+                _ => "updating",
+            };
+            println!("\x1b[1m\x1b[92m{}\x1b[0m: {}", action, site.position);
+            let file = self
+                .per_file
+                .entry(site.position.file)
+                .or_insert_with_key(|&filename| FileRuntime::new(filename, mode));
+            if let Err(err) = file.rewrite_unordered(site, &actual) {
+                eprintln!(
+                    "\x1b[1m\x1b[91merror\x1b[0m: unable to update {}: {}",
+                    site.position, err
+                );
+            }
+        }
     }
-    fn panic(&mut self, position: &FilePosition, expected: &str, actual: &str) {
+    /// Fails the test of an [`ExpectScope`](crate::scope::ExpectScope) that
+    /// collected mismatches or saw expects with unused literals.
+    pub(crate) fn fail_scope(failures: &[Failure], mismatches: Option<&str>) {
+        {
+            let mut rt = RT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            for failure in failures {
+                rt.print_failure(&failure.position, &failure.expected, &failure.actual);
+            }
+        }
+        if let Some(mismatches) = mismatches {
+            println!(
+                "\n\x1b[1m\x1b[91merror\x1b[97m: expects did not use all of their expected literals\x1b[0m
+{}Run with `UPDATE_EXPECT=1` to remove the rest.",
+                mismatches
+            );
+        }
+        if failures.len() > 1 {
+            println!("{} expects failed", failures.len());
+        }
+        // The test might be failing already, with the scope dropped while unwinding
+        if !std::thread::panicking() {
+            fail_test();
+        }
+    }
+    /// Takes back `count` failures that a test of this crate made on purpose,
+    /// so that a passing test run doesn't report failed expects.
+    #[cfg(test)]
+    pub(crate) fn forget_failures(file: &str, count: usize) {
+        let mut rt = RT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let failures = rt.failures.get_mut(file).expect("no failures to forget");
+        *failures -= count;
+        if *failures == 0 {
+            rt.failures.remove(file);
+        }
+    }
+    pub(crate) fn set_reported(positions: Vec<FilePosition>) {
+        RT.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .reported
+            .extend(positions);
+    }
+    /// Fails the running test with the unused literals of tests that already
+    /// finished, which can't fail themselves anymore.
+    pub(crate) fn fail_finished_tests(finished: &[FinishedTest]) {
+        RT.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .mark_reported(finished);
+        Runtime::fail_scope(&[], Some(&scope::describe_finished_tests(finished)));
+    }
+    pub(crate) fn has_panicked(thread: ThreadId) -> bool {
+        PANICKED
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .contains(&thread)
+    }
+    fn panic(&mut self, position: &FilePosition, expected: &str, actual: &str) -> ! {
+        self.print_failure(position, expected, actual);
+        fail_test()
+    }
+    fn print_failure(&mut self, position: &FilePosition, expected: &str, actual: &str) {
         let print_help = !std::mem::replace(&mut self.help_printed, true);
         let help = if print_help { HELP } else { "" };
 
@@ -73,74 +453,351 @@ impl Runtime {
             actual,
             format_chunks(diff)
         );
-        // Use resume_unwind instead of panic!() to prevent a backtrace, which is unnecessary noise.
-        std::panic::resume_unwind(Box::new(()));
     }
 }
 
-struct FileRuntime {
+fn fail_test() -> ! {
+    // resume_unwind skips the panic hook, so remember this thread failed ourselves
+    PANICKED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(std::thread::current().id());
+    // Use resume_unwind instead of panic!() to prevent a backtrace, which is unnecessary noise.
+    std::panic::resume_unwind(Box::new(()));
+}
+
+pub(crate) struct FileRuntime {
     path: PathBuf,
+    /// Where updates are written: `path` itself, or its shadow copy for `UPDATE_EXPECT=patch`
+    target: PathBuf,
+    /// The file as it was when the test binary was built, which is what
+    /// `line!()`, `column!()` and all of our patches refer to
     original_text: String,
-    patchwork: Patchwork,
+    /// Set if the file was saved after the test binary was built, in which
+    /// case `original_text` isn't what the positions refer to
+    modified_after_build: bool,
+    /// The lines of every updated expect, used to find them again if the file
+    /// gets modified while the tests are running
+    regions: Vec<Range<usize>>,
+    /// The changes to every updated macro call, applied when flushing
+    calls: Vec<CallEdit>,
+    style: SourceStyle,
+    /// Number of expects updated so far
+    updated: usize,
+    /// The keys updated so far at every keyed expect, in order
+    updated_keys: HashMap<FilePosition, Vec<String>>,
 }
 
 impl FileRuntime {
-    fn new(filename: &str) -> FileRuntime {
+    pub(crate) fn new(filename: &str, mode: UpdateMode) -> FileRuntime {
         let path = to_abs_ws_path(Path::new(filename));
-        let original_text = std::fs::read_to_string(&path).unwrap();
-        let patchwork = Patchwork::new(original_text.clone());
+        let target = match mode {
+            UpdateMode::Patch => patch_file::shadow_path(&path),
+            _ => path.clone(),
+        };
+        let (original_text, modified_after_build) = FileRuntime::read_original_text(&path, &target)
+            .unwrap_or_else(|err| panic!("Unable to read {}: {}", path.display(), err));
+        let style = SourceStyle::of_file(&path, &original_text);
         FileRuntime {
             path,
+            target,
             original_text,
-            patchwork,
+            modified_after_build,
+            regions: Vec::new(),
+            calls: Vec::new(),
+            style,
+            updated: 0,
+            updated_keys: HashMap::new(),
         }
     }
-    fn update<const N: usize>(&mut self, expect: &Expect<N>, actual: &str) {
-        let index = expect.assertion_index;
-        let loc = expect.find_expect_location(&self.original_text);
-
-        let patch = format_patch(loc.line_indent, actual);
-        if let Some(expected_range) = loc.expected_ranges.get(index) {
-            // TODO-someday: we need another case for if we convert a single line to a multiline
-            // accepting something like `expect!("ABC\nDEF", "ABC");` will give bad formatting
-            // TODO-someday: what happens if some arguments are multiline and others are not?
-            self.patchwork
-                .patch_range(expected_range.clone(), &patch, PatchOrdering::Normal);
+    /// Another test process may have updated the file already, in which case
+    /// its journal still has the text our positions refer to.
+    fn read_original_text(path: &Path, target: &Path) -> io::Result<(String, bool)> {
+        let _lock = journal::lock(path)?;
+        let journal = if target == path {
+            Journal::load(target)?
         } else {
-            let is_multiline = patch.contains('\n');
-            let is_first_assertion = expect.assertion_index == 0;
-
-            // TODO-someday: if we're the first assertion, we should queue
-            // deletion of all other arguments - we assume that this expect is
-            // never called again
-
-            let indent = " ".repeat(loc.line_indent);
-            if is_multiline && is_first_assertion {
-                self.patchwork.patch_insert(
-                    loc.start_index,
-                    &format!("\n{indent}    "),
-                    PatchOrdering::BeforeOtherPatches,
-                );
-                self.patchwork.patch_insert(
-                    loc.end_index,
-                    &format!("\n{indent}"),
-                    PatchOrdering::AfterOtherPatches,
-                );
+            patch_file::shadow_journal(&std::fs::read_to_string(path)?, target)?
+        };
+        if let Some(journal) = journal {
+            return Ok((journal.base_text, false));
+        }
+        let modified = std::fs::metadata(path)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let modified_after_build = journal::build_time().is_ok_and(|built| modified > built);
+        Ok((std::fs::read_to_string(path)?, modified_after_build))
+    }
+    pub(crate) fn update<const N: usize>(
+        &mut self,
+        expect: &Expect<N>,
+        key: Option<&str>,
+        actual: &str,
+    ) {
+        if self.modified_after_build {
+            panic!(
+                "Unable to update expect at {}: {} was modified after the test binary was built, re-run the tests",
+                expect.file_position,
+                self.path.display()
+            );
+        }
+        let loc = expect
+            .find_expect_location(&self.original_text)
+            .unwrap_or_else(|err| {
+                panic!(
+                    "Unable to locate expect at {}: {}",
+                    expect.file_position, err
+                )
+            });
+        let mut literal_index = expect.literal_index(key);
+        if let Some(key) = key {
+            let updated_keys = self
+                .updated_keys
+                .entry(expect.file_position.clone())
+                .or_default();
+            // The first value seen for a key wins, patching it twice would clash
+            if updated_keys.iter().any(|updated| updated == key) {
+                return;
             }
-            // TODO-someday: what happens if some arguments are multiline and others are not?
-            let patch = if is_multiline {
-                format!(",\n{indent}    {patch}")
-            } else {
-                format!(", {}", patch)
+            // New keys are appended after the ones added before them
+            if literal_index >= N {
+                literal_index += updated_keys
+                    .iter()
+                    .filter(|&updated| expect.literal_index(Some(updated)) >= N)
+                    .count();
+            }
+            updated_keys.push(key.to_string());
+        }
+        self.call_edit(loc).set(literal_index, key, actual);
+        self.updated += 1;
+    }
+    /// Deletes the expected literals after the first `site.count`, which were never reached.
+    fn remove_surplus(&mut self, site: &SiteCalls) -> Result<(), String> {
+        let loc = self.find_site(site)?;
+        self.call_edit(loc).truncate(site.count);
+        self.updated += 1;
+        Ok(())
+    }
+    /// Writes `values` in place of the expected literals of an `expect_unordered!`.
+    fn rewrite_unordered(&mut self, site: &SiteCalls, values: &[String]) -> Result<(), String> {
+        let call = self.call_edit(self.find_site(site)?);
+        for (index, value) in values.iter().enumerate() {
+            call.set(index, None, value);
+        }
+        call.truncate(values.len());
+        self.updated += 1;
+        Ok(())
+    }
+    fn find_site(&self, site: &SiteCalls) -> Result<ExpectLocation, String> {
+        if self.modified_after_build {
+            return Err(format!(
+                "{} was modified after the test binary was built, re-run the tests",
+                self.path.display()
+            ));
+        }
+        find_location(
+            &self.original_text,
+            site.position.line,
+            site.position.column,
+            site.has_actual,
+            site.num_expected,
+        )
+        .map_err(|err| err.to_string())
+    }
+    /// The edit of the call at `loc`, which is started if there is none yet.
+    fn call_edit(&mut self, loc: ExpectLocation) -> &mut CallEdit {
+        let index = match self
+            .calls
+            .iter()
+            .position(|call| call.loc().body_range == loc.body_range)
+        {
+            Some(index) => index,
+            None => {
+                self.add_region(&loc);
+                self.calls.push(CallEdit::new(loc));
+                self.calls.len() - 1
+            }
+        };
+        &mut self.calls[index]
+    }
+    fn add_region(&mut self, loc: &ExpectLocation) {
+        let region_start = self.original_text[..loc.body_range.start]
+            .rfind('\n')
+            .map_or(0, |newline| newline + 1);
+        let region_end = self.original_text[loc.body_range.end..]
+            .find('\n')
+            .map_or(self.original_text.len(), |newline| {
+                loc.body_range.end + newline
+            });
+        if !self.regions.contains(&(region_start..region_end)) {
+            self.regions.push(region_start..region_end);
+        }
+    }
+    /// Rebases our patches onto whatever was written to the file in the
+    /// meantime, then records them in the journal for other test processes.
+    /// A dry run prints the diff instead of writing anything.
+    pub(crate) fn flush(&self, dry_run: bool) -> io::Result<()> {
+        let mut ours = Patchwork::new(self.original_text.clone());
+        for call in &self.calls {
+            call.apply(&self.original_text, &self.style, &mut ours);
+        }
+        let edits = ours.edits();
+        if edits.is_empty() {
+            return Ok(());
+        }
+        let _lock = journal::lock(&self.path)?;
+        if self.target != self.path {
+            let source_text = std::fs::read_to_string(&self.path)?;
+            if patch_file::shadow_journal(&source_text, &self.target)?.is_none() {
+                patch_file::create_shadow(&self.path, &self.target)?;
+            }
+        }
+        let current_text = std::fs::read_to_string(&self.target)?;
+        let current_hash = content_hash(&current_text);
+        let original_hash = content_hash(&self.original_text);
+        let journal =
+            Journal::load(&self.target)?.filter(|journal| journal.base_hash() == original_hash);
+        let entries = match &journal {
+            _ if current_hash == original_hash => Some(&[][..]),
+            Some(journal) => journal.entries_between(original_hash, current_hash),
+            None => None,
+        };
+
+        let mut patchwork = Patchwork::new(current_text.clone());
+        let mut applied = Vec::new();
+        for (range, inserted) in edits {
+            let range = match entries {
+                // Only test processes wrote to the file, so we know exactly what they did
+                Some(entries) => match rebase_edit(&range, inserted, entries, &current_text) {
+                    Rebased::Moved(range) => range,
+                    Rebased::AlreadyMade => continue,
+                    Rebased::Conflict => {
+                        return Err(io::Error::other(format!(
+                            "another test process updated the same expect in {}",
+                            self.path.display()
+                        )))
+                    }
+                },
+                // Something else modified the file, so find our expects again by their text
+                None => self.relocate(&range, &current_text).ok_or_else(|| {
+                    io::Error::other(format!(
+                        "{} was modified while the tests were running and the updated expects could not be found again, re-run the tests",
+                        self.path.display()
+                    ))
+                })?,
             };
-            self.patchwork
-                .patch_insert(loc.end_index, &patch, PatchOrdering::Normal);
+            patchwork.patch_range(range.clone(), inserted, PatchOrdering::Normal);
+            applied.push(Edit {
+                range,
+                inserted_len: inserted.len(),
+            });
         }
-        std::fs::write(&self.path, self.patchwork.text()).unwrap()
+        if applied.is_empty() {
+            return Ok(());
+        }
+        if dry_run {
+            let name = self
+                .path
+                .strip_prefix(workspace_root())
+                .unwrap_or(&self.path);
+            print!(
+                "{}",
+                unified_diff(&name.to_string_lossy(), &current_text, patchwork.text())
+            );
+            return Ok(());
+        }
+
+        let has_journal = match journal {
+            Some(_) => true,
+            None if current_hash == original_hash => {
+                Journal::start(&self.target, &current_text)?;
+                true
+            }
+            None => false,
+        };
+        write_atomically(&self.target, patchwork.text())?;
+        if !has_journal {
+            return Ok(());
+        }
+        applied.sort_by_key(|edit| (edit.range.start, edit.range.end));
+        Journal::append(
+            &self.target,
+            &JournalEntry {
+                from_hash: current_hash,
+                to_hash: content_hash(patchwork.text()),
+                edits: applied,
+            },
+        )
+    }
+    /// Moves `range` along with the lines of the expect it belongs to.
+    fn relocate(&self, range: &Range<usize>, current_text: &str) -> Option<Range<usize>> {
+        let region = self
+            .regions
+            .iter()
+            .find(|region| region.start <= range.start && range.end <= region.end)?;
+        let start = relocate_region(&self.original_text, region, current_text)?;
+        Some(start + range.start - region.start..start + range.end - region.start)
+    }
+}
+
+fn watch_panics() {
+    static WATCH: Once = Once::new();
+    // Swapping the panic hook while panicking would abort
+    if std::thread::panicking() {
+        return;
+    }
+    WATCH.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANICKED
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .insert(std::thread::current().id());
+            previous(info)
+        }));
+    });
+}
+
+fn register_exit_hook() {
+    extern "C" {
+        fn atexit(callback: extern "C" fn()) -> std::os::raw::c_int;
+    }
+    extern "C" fn finish_at_exit() {
+        // Unwinding out of an atexit handler would abort the process
+        let _ = std::panic::catch_unwind(|| {
+            RT.lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .finish()
+        });
     }
+
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        // SAFETY: `finish_at_exit` takes no arguments and never unwinds
+        let result = unsafe { atexit(finish_at_exit) };
+        assert_eq!(result, 0, "Unable to register exit hook for expect-tests");
+    });
+}
+
+/// The literal that differs the least from `actual`, to show the diff against.
+fn closest<'a>(literals: &'a [String], actual: &str) -> &'a str {
+    let difference = |literal: &&String| {
+        dissimilar::diff(literal, actual)
+            .iter()
+            .map(|chunk| match chunk {
+                dissimilar::Chunk::Equal(_) => 0,
+                dissimilar::Chunk::Delete(text) | dissimilar::Chunk::Insert(text) => text.len(),
+            })
+            .sum::<usize>()
+    };
+    literals
+        .iter()
+        .min_by_key(difference)
+        .map_or("", String::as_str)
 }
 
-fn format_chunks(chunks: Vec<dissimilar::Chunk>) -> String {
+pub fn format_chunks(chunks: Vec<dissimilar::Chunk>) -> String {
     let mut buf = String::new();
     for chunk in chunks {
         let formatted = match chunk {
@@ -153,64 +810,90 @@ fn format_chunks(chunks: Vec<dissimilar::Chunk>) -> String {
     buf
 }
 
-fn to_abs_ws_path(path: &Path) -> PathBuf {
+pub fn to_abs_ws_path(path: &Path) -> PathBuf {
     if path.is_absolute() {
         return path.to_owned();
     }
+    workspace_root().join(path)
+}
 
+pub fn workspace_root() -> &'static Path {
     static WORKSPACE_ROOT: OnceCell<PathBuf> = OnceCell::new();
-    WORKSPACE_ROOT
-        .get_or_try_init(|| {
-            // Until https://github.com/rust-lang/cargo/issues/3946 is resolved, this
-            // is set with a hack like https://github.com/rust-lang/cargo/issues/3946#issuecomment-973132993
-            if let Ok(workspace_root) = std::env::var("CARGO_WORKSPACE_DIR") {
-                return Ok(workspace_root.into());
-            }
-
-            // If a hack isn't used, we use a heuristic to find the "top-level" workspace.
-            // This fails in some cases, see https://github.com/rust-analyzer/expect-test/issues/33
-            let my_manifest = std::env::var("CARGO_MANIFEST_DIR")?;
-            let workspace_root = Path::new(&my_manifest)
-                .ancestors()
-                .filter(|it| it.join("Cargo.toml").exists())
-                .last()
-                .unwrap()
-                .to_path_buf();
-
-            Ok(workspace_root)
-        })
-        .unwrap_or_else(|_: std::env::VarError| {
-            panic!(
-                "No CARGO_MANIFEST_DIR env var and the path is relative: {}",
-                path.display()
-            )
-        })
-        .join(path)
+    WORKSPACE_ROOT.get_or_init(|| {
+        // Until https://github.com/rust-lang/cargo/issues/3946 is resolved, this
+        // is set with a hack like https://github.com/rust-lang/cargo/issues/3946#issuecomment-973132993
+        if let Ok(workspace_root) = std::env::var("CARGO_WORKSPACE_DIR") {
+            return workspace_root.into();
+        }
+
+        // If a hack isn't used, we use a heuristic to find the "top-level" workspace.
+        // This fails in some cases, see https://github.com/rust-analyzer/expect-test/issues/33
+        // Outside of `cargo test` (e.g. in `cargo expect`) we start from the current directory.
+        let start = std::env::var("CARGO_MANIFEST_DIR")
+            .map(PathBuf::from)
+            .or_else(|_| std::env::current_dir())
+            .expect("No CARGO_MANIFEST_DIR env var and no current directory");
+        start
+            .ancestors()
+            .filter(|it| it.join("Cargo.toml").exists())
+            .last()
+            .unwrap_or(&start)
+            .to_path_buf()
+    })
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The target directory of the fixture that a test of this crate runs in,
+    /// so it doesn't write into the workspace `target/`
+    pub(crate) static TEST_TARGET_DIR: std::cell::RefCell<Option<PathBuf>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Directory for state that outlives a single test process, such as pending snapshots.
+pub fn expect_tests_dir() -> PathBuf {
+    #[cfg(test)]
+    if let Some(target_dir) = TEST_TARGET_DIR.with_borrow(Clone::clone) {
+        return target_dir.join("expect-tests");
+    }
+    let target_dir = match std::env::var_os("CARGO_TARGET_DIR") {
+        Some(target_dir) => to_abs_ws_path(Path::new(&target_dir)),
+        None => workspace_root().join("target"),
+    };
+    target_dir.join("expect-tests")
+}
+
+/// The 64-bit FNV-1a hash of `text`. Hashes end up on disk and are compared
+/// by `cargo expect review`, which may be built by another Rust version than
+/// the tests, so `DefaultHasher` won't do.
+pub fn content_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
 }
 
-pub fn format_patch(desired_indent: usize, patch: &str) -> String {
-    let lit_kind = StrLitKind::from(patch);
-    let indent = " ".repeat(desired_indent);
+/// Formats `patch` as a `lit_kind` string literal, indenting the lines of a
+/// multiline one by `indent`.
+pub fn format_patch(indent: &str, lit_kind: StrLitKind, patch: &str) -> String {
     let is_multiline = patch.contains('\n');
 
     let mut buf = String::new();
     lit_kind.write_start(&mut buf).unwrap();
     if is_multiline {
-        for line in patch.lines() {
+        // Not `lines()`, which would drop the `\r` of CRLF line endings
+        for line in patch.strip_suffix('\n').unwrap_or(patch).split('\n') {
             buf.push('\n');
-            if !line.trim().is_empty() {
-                buf.push_str(&indent);
-                buf.push_str("    ");
+            if !line.trim_matches([' ', '\t']).is_empty() {
+                buf.push_str(indent);
             }
-            buf.push_str(line);
+            lit_kind.write_contents(&mut buf, line).unwrap();
         }
         if patch.ends_with('\n') {
             buf.push('\n');
-            buf.push_str(&indent);
-            buf.push_str("    ");
+            buf.push_str(indent);
         }
     } else {
-        buf.push_str(patch);
+        lit_kind.write_contents(&mut buf, patch).unwrap();
     }
     lit_kind.write_end(&mut buf).unwrap();
     buf
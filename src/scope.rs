@@ -0,0 +1,390 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Write,
+    marker::PhantomData,
+    sync::{Arc, Mutex, MutexGuard},
+    thread::ThreadId,
+};
+
+use crate::{expect::FilePosition, runtime::Runtime, summary::plural, update_mode::UpdateMode};
+
+thread_local! {
+    /// Used by expects that run outside of any scope. Every test runs on a
+    /// thread of its own, so this makes assertion indices count per test.
+    static THREAD_CONTEXT: ThreadContext = ThreadContext::new();
+    /// Every scope that is active on this thread, and every context entered
+    /// from another thread, innermost last
+    static SCOPES: RefCell<Vec<Arc<Mutex<Context>>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The unused literals of tests that finished outside of any scope, which the
+/// next expect reports since a test can't fail anymore once it's done.
+static FINISHED: Mutex<Vec<FinishedTest>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Default)]
+struct Context {
+    /// How many times every expect ran so far
+    calls: HashMap<FilePosition, usize>,
+    /// Number of expected literals of every expect with several of them
+    num_expected: HashMap<FilePosition, usize>,
+    /// The values every `expect_unordered!` saw so far
+    unordered: HashMap<FilePosition, Vec<String>>,
+    /// Whether mismatches are collected in `failures` instead of failing right away
+    soft: bool,
+    failures: Vec<Failure>,
+}
+
+/// A mismatch that a soft scope reports when it's dropped.
+#[derive(Debug)]
+pub(crate) struct Failure {
+    pub position: FilePosition,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// A test that finished without using all the literals of its expects.
+#[derive(Debug)]
+pub(crate) struct FinishedTest {
+    pub test: String,
+    pub positions: Vec<FilePosition>,
+    pub mismatches: String,
+}
+
+/// The context of the test running on a thread, which is checked for unused
+/// literals when the thread exits.
+struct ThreadContext {
+    context: Arc<Mutex<Context>>,
+    /// libtest names the thread of every test after the test
+    test: Option<String>,
+    thread: ThreadId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SiteCount {
+    pub num_expected: usize,
+    pub calls: usize,
+}
+
+/// Gives the expects that run while it's alive their own assertion indices,
+/// starting over from the first expected literal, and makes sure that every
+/// expect with several expected literals uses exactly as many literals as it
+/// has. Otherwise the test fails when the scope is dropped.
+///
+/// A scope created with [`ExpectScope::soft`] also collects the mismatches of
+/// the expects within it, and fails once with all of them when it's dropped,
+/// instead of failing on the first one.
+///
+/// `#[expect_tests::test]` creates one around the whole test, and
+/// `#[expect_tests::test(soft)]` a soft one. Harnesses that
+/// run the same test body several times on one thread can create one per run.
+/// Only expects running on the same thread as the scope are counted, unless
+/// another thread enters its [`ExpectContext`], and expects inside a nested
+/// scope only count towards the innermost one.
+#[must_use = "the scope checks the expects when it's dropped, bind it with `let _scope = ...`"]
+pub struct ExpectScope {
+    depth: usize,
+    // The scope belongs to the thread it counts calls on
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ExpectScope {
+    pub fn new() -> ExpectScope {
+        ExpectScope::push(Context::default())
+    }
+
+    /// Same as [`ExpectScope::new`], but lets the test run to the end when an
+    /// expect doesn't match, so all the mismatches are shown together.
+    pub fn soft() -> ExpectScope {
+        ExpectScope::push(Context {
+            soft: true,
+            ..Context::default()
+        })
+    }
+
+    fn push(context: Context) -> ExpectScope {
+        ExpectScope {
+            depth: push(Arc::new(Mutex::new(context))),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for ExpectScope {
+    fn default() -> ExpectScope {
+        ExpectScope::new()
+    }
+}
+
+impl Drop for ExpectScope {
+    fn drop(&mut self) {
+        let context = SCOPES.with(|scopes| {
+            let mut scopes = scopes.borrow_mut();
+            // Inner scopes that outlive us stop counting as well
+            scopes.truncate(self.depth);
+            scopes.pop()
+        });
+        let Some(context) = context else {
+            return;
+        };
+        let context = lock(&context);
+        // A failing test already failed, and updates remove the surplus literals at exit
+        let check_counts = !std::thread::panicking()
+            && !UpdateMode::from_env().is_some_and(UpdateMode::removes_surplus);
+        let mismatches = check_counts
+            .then(|| describe_mismatches(&context.site_counts()))
+            .flatten();
+        if mismatches.is_some() {
+            // Not to be reported once more by the check at exit
+            Runtime::set_reported(context.mismatched_sites());
+        }
+        if !context.failures.is_empty() || mismatches.is_some() {
+            Runtime::fail_scope(&context.failures, mismatches.as_deref());
+        }
+    }
+}
+
+impl ThreadContext {
+    fn new() -> ThreadContext {
+        let thread = std::thread::current();
+        ThreadContext {
+            context: Arc::default(),
+            test: thread.name().map(str::to_string),
+            thread: thread.id(),
+        }
+    }
+}
+
+impl Drop for ThreadContext {
+    fn drop(&mut self) {
+        let Some(test) = self.test.take() else {
+            return;
+        };
+        // A failing test already failed, and updates remove the surplus literals at exit
+        if Runtime::has_panicked(self.thread)
+            || UpdateMode::from_env().is_some_and(UpdateMode::removes_surplus)
+        {
+            return;
+        }
+        let context = lock(&self.context);
+        let Some(mismatches) = describe_mismatches(&context.site_counts()) else {
+            return;
+        };
+        lock(&FINISHED).push(FinishedTest {
+            test,
+            positions: context.mismatched_sites(),
+            mismatches,
+        });
+    }
+}
+
+impl Context {
+    fn site_counts(&self) -> HashMap<FilePosition, SiteCount> {
+        self.num_expected
+            .iter()
+            .map(|(position, &num_expected)| {
+                let calls = self.calls.get(position).copied().unwrap_or(0);
+                (
+                    position.clone(),
+                    SiteCount {
+                        num_expected,
+                        calls,
+                    },
+                )
+            })
+            .collect()
+    }
+    /// The sites that didn't run once per expected literal
+    fn mismatched_sites(&self) -> Vec<FilePosition> {
+        self.site_counts()
+            .into_iter()
+            .filter(|(_, count)| count.calls != count.num_expected)
+            .map(|(position, _)| position)
+            .collect()
+    }
+}
+
+/// The assertion counts of the current test or scope, to hand to the
+/// threads a test spawns. Expects running on another thread count on their
+/// own, unless that thread enters the context of the test:
+///
+/// ```
+/// use expect_tests::{expect, scope::ExpectContext};
+///
+/// let context = ExpectContext::current();
+/// std::thread::spawn(move || {
+///     let _context = context.enter();
+///     expect!(1 + 1, "2");
+/// })
+/// .join()
+/// .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ExpectContext(Arc<Mutex<Context>>);
+
+impl ExpectContext {
+    /// The context of the innermost scope on this thread, or else of the
+    /// test running on it.
+    pub fn current() -> ExpectContext {
+        ExpectContext(current())
+    }
+
+    /// Makes the expects on this thread count in this context until the
+    /// returned guard is dropped.
+    pub fn enter(&self) -> EnteredContext {
+        EnteredContext {
+            depth: push(self.0.clone()),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// Returned by [`ExpectContext::enter`], leaves the context when it's dropped.
+#[must_use = "the context is left when this is dropped, bind it with `let _context = ...`"]
+pub struct EnteredContext {
+    depth: usize,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for EnteredContext {
+    fn drop(&mut self) {
+        SCOPES.with(|scopes| scopes.borrow_mut().truncate(self.depth - 1));
+    }
+}
+
+/// Makes `context` the innermost one on this thread, returning its depth.
+fn push(context: Arc<Mutex<Context>>) -> usize {
+    SCOPES.with(|scopes| {
+        let mut scopes = scopes.borrow_mut();
+        scopes.push(context);
+        scopes.len()
+    })
+}
+
+fn current() -> Arc<Mutex<Context>> {
+    SCOPES
+        .with(|scopes| scopes.borrow().last().cloned())
+        .unwrap_or_else(|| THREAD_CONTEXT.with(|thread| thread.context.clone()))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn with_context<T>(f: impl FnOnce(&mut Context) -> T) -> T {
+    let context = current();
+    let result = f(&mut lock(&context));
+    result
+}
+
+/// The index of the expected literal to compare against, which counts the
+/// runs of the expect at `position` within the current test or scope.
+#[doc(hidden)]
+pub fn next_assertion_index(position: &FilePosition) -> usize {
+    let finished = take_finished_tests();
+    if !finished.is_empty() {
+        Runtime::fail_finished_tests(&finished);
+    }
+    with_context(|context| {
+        let calls = context.calls.entry(position.clone()).or_default();
+        *calls += 1;
+        *calls - 1
+    })
+}
+
+/// Remembers that the expect at `position` has several expected literals, so
+/// the innermost scope can check they were all used.
+pub fn record(position: &FilePosition, num_expected: usize) {
+    with_context(|context| {
+        context.num_expected.insert(position.clone(), num_expected);
+    });
+}
+
+/// Matches `actual` against the expected literals of an unordered expect that
+/// no earlier call in the current test or scope matched. Returns those
+/// literals if `actual` isn't one of them.
+pub(crate) fn match_unordered(
+    position: &FilePosition,
+    expected: &[String],
+    actual: &str,
+) -> Result<(), Vec<String>> {
+    with_context(|context| {
+        let seen = context.unordered.entry(position.clone()).or_default();
+        let mut remaining = expected.to_vec();
+        for value in seen.iter() {
+            if let Some(index) = remaining.iter().position(|literal| literal == value) {
+                remaining.remove(index);
+            }
+        }
+        seen.push(actual.to_string());
+        if remaining.iter().any(|literal| literal == actual) {
+            Ok(())
+        } else {
+            Err(remaining)
+        }
+    })
+}
+
+/// Hands a mismatch to the innermost soft scope, if there is one. Returns
+/// whether it did, otherwise the expect has to fail right away.
+pub(crate) fn defer_failure(position: &FilePosition, expected: &str, actual: &str) -> bool {
+    SCOPES.with(|scopes| {
+        let scopes = scopes.borrow();
+        let Some(mut context) = scopes
+            .iter()
+            .rev()
+            .map(|context| lock(context))
+            .find(|context| context.soft)
+        else {
+            return false;
+        };
+        context.failures.push(Failure {
+            position: position.clone(),
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        });
+        true
+    })
+}
+
+pub(crate) fn take_finished_tests() -> Vec<FinishedTest> {
+    std::mem::take(&mut *lock(&FINISHED))
+}
+
+/// Lists the sites of every finished test that didn't run once per expected literal.
+pub(crate) fn describe_finished_tests(finished: &[FinishedTest]) -> String {
+    let mut buf = String::new();
+    for finished in finished {
+        writeln!(buf, "   in `{}`, which already finished:", finished.test).unwrap();
+        for line in finished.mismatches.lines() {
+            writeln!(buf, "    {}", line).unwrap();
+        }
+    }
+    buf
+}
+
+/// Lists the sites that didn't run once per expected literal, if any.
+pub fn describe_mismatches(sites: &HashMap<FilePosition, SiteCount>) -> Option<String> {
+    let mut mismatches = sites
+        .iter()
+        .filter(|(_, count)| count.calls != count.num_expected)
+        .collect::<Vec<_>>();
+    if mismatches.is_empty() {
+        return None;
+    }
+    mismatches.sort_by_key(|(position, _)| (position.file, position.line, position.column));
+    let mut buf = String::new();
+    for (position, count) in mismatches {
+        writeln!(
+            buf,
+            "   {} ran {} but has {} expected literals",
+            position,
+            plural(count.calls, "time"),
+            count.num_expected
+        )
+        .unwrap();
+    }
+    Some(buf)
+}
@@ -0,0 +1,128 @@
+use std::{collections::BTreeMap, path::Path};
+
+use crate::str_lit_kind::LiteralStyle;
+
+/// How a source file is formatted, so updated expects look like the code
+/// around them. Comes from the `rustfmt.toml` closest to the file, and is
+/// detected from the file itself for whatever that doesn't set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceStyle {
+    /// One level of indentation, either a tab or a number of spaces
+    pub indent: String,
+    /// How many columns a tab takes up
+    pub tab_spaces: usize,
+    /// Lines longer than this get split up
+    pub max_width: usize,
+    /// What lines end with, `"\n"` or `"\r\n"`
+    pub newline: &'static str,
+    /// The kind of literal for new expected values
+    pub literals: LiteralStyle,
+}
+
+impl Default for SourceStyle {
+    fn default() -> SourceStyle {
+        SourceStyle {
+            indent: "    ".to_string(),
+            tab_spaces: 4,
+            max_width: 100,
+            newline: "\n",
+            literals: LiteralStyle::default(),
+        }
+    }
+}
+
+impl SourceStyle {
+    pub fn of_file(path: &Path, text: &str) -> SourceStyle {
+        let config = path
+            .ancestors()
+            .skip(1)
+            .find_map(|dir| {
+                ["rustfmt.toml", ".rustfmt.toml"]
+                    .iter()
+                    .find_map(|name| std::fs::read_to_string(dir.join(name)).ok())
+            })
+            .unwrap_or_default();
+        SourceStyle {
+            literals: LiteralStyle::from_env(),
+            ..SourceStyle::from_config(&config, text)
+        }
+    }
+
+    /// Reads the options we care about from the contents of a `rustfmt.toml`,
+    /// all of which are plain `key = value` lines.
+    pub fn from_config(config: &str, text: &str) -> SourceStyle {
+        let option = |name: &str| {
+            config.lines().find_map(|line| {
+                let line = line.split('#').next().unwrap_or_default();
+                let (key, value) = line.split_once('=')?;
+                (key.trim() == name).then(|| value.trim().to_string())
+            })
+        };
+        let hard_tabs = option("hard_tabs").map(|value| value == "true");
+        let tab_spaces = option("tab_spaces").and_then(|value| value.parse::<usize>().ok());
+        let max_width = option("max_width").and_then(|value| value.parse::<usize>().ok());
+        let newline = match option("newline_style").as_deref() {
+            Some("\"Windows\"") => "\r\n",
+            Some("\"Unix\"") => "\n",
+            Some("\"Native\"") if cfg!(windows) => "\r\n",
+            Some("\"Native\"") => "\n",
+            // Like rustfmt's default `Auto`, whatever the first line ends with
+            _ => match text.find('\n') {
+                Some(index) if text[..index].ends_with('\r') => "\r\n",
+                _ => "\n",
+            },
+        };
+        let detected = detect_indent(text);
+        let indent = if hard_tabs.unwrap_or(detected.as_deref() == Some("\t")) {
+            "\t".to_string()
+        } else if let Some(tab_spaces) = tab_spaces {
+            " ".repeat(tab_spaces)
+        } else {
+            detected
+                .filter(|detected| detected != "\t")
+                .unwrap_or_else(|| SourceStyle::default().indent)
+        };
+        let default = SourceStyle::default();
+        SourceStyle {
+            indent,
+            tab_spaces: tab_spaces.unwrap_or(default.tab_spaces),
+            max_width: max_width.unwrap_or(default.max_width),
+            newline,
+            literals: default.literals,
+        }
+    }
+
+    /// Ends the lines of `text` with our newlines.
+    pub fn with_newlines(&self, text: &str) -> String {
+        text.replace("\r\n", "\n").replace('\n', self.newline)
+    }
+
+    /// The number of columns `line` takes up.
+    pub fn width(&self, line: &str) -> usize {
+        line.chars()
+            .map(|c| if c == '\t' { self.tab_spaces } else { 1 })
+            .sum()
+    }
+}
+
+/// The indentation `text` adds most often from one line to the next, if that
+/// is a tab or a few spaces.
+fn detect_indent(text: &str) -> Option<String> {
+    let mut counts = BTreeMap::<&str, usize>::new();
+    let mut previous = "";
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        if let Some(increase) = indent.strip_prefix(previous) {
+            let is_spaces =
+                (1..=8).contains(&increase.len()) && increase.bytes().all(|b| b == b' ');
+            if increase == "\t" || is_spaces {
+                *counts.entry(increase).or_default() += 1;
+            }
+        }
+        previous = indent;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(_, count)| count)
+        .map(|(indent, _)| indent.to_string())
+}
@@ -2,12 +2,13 @@
 pub enum StrLitKind {
     Normal,     // use ""
     Raw(usize), // use r#""# with variable number of #'s
+    Escaped,    // use "" with escapes like \x1b for control characters
 }
 
 impl StrLitKind {
     pub fn write_start(&self, w: &mut impl std::fmt::Write) -> std::fmt::Result {
         match self {
-            Self::Normal => write!(w, "\""),
+            Self::Normal | Self::Escaped => write!(w, "\""),
             Self::Raw(n) => {
                 write!(w, "r")?;
                 for _ in 0..*n {
@@ -20,7 +21,7 @@ impl StrLitKind {
 
     pub fn write_end(&self, w: &mut impl std::fmt::Write) -> std::fmt::Result {
         match self {
-            Self::Normal => write!(w, "\""),
+            Self::Normal | Self::Escaped => write!(w, "\""),
             Self::Raw(n) => {
                 write!(w, "\"")?;
                 for _ in 0..*n {
@@ -30,20 +31,131 @@ impl StrLitKind {
             }
         }
     }
+
+    /// Writes `s` as the contents of the literal. Only escaped literals change
+    /// anything, and they keep newlines as they are so multiline text stays readable.
+    pub fn write_contents(&self, w: &mut impl std::fmt::Write, s: &str) -> std::fmt::Result {
+        if !matches!(self, Self::Escaped) {
+            return w.write_str(s);
+        }
+        for c in s.chars() {
+            match c {
+                '\n' => w.write_char('\n')?,
+                '\\' | '"' => write!(w, "\\{}", c)?,
+                '\t' => w.write_str("\\t")?,
+                '\r' => w.write_str("\\r")?,
+                '\0' => w.write_str("\\0")?,
+                c if needs_escape(c) && c.is_ascii() => write!(w, "\\x{:02x}", c as u32)?,
+                c if needs_escape(c) => write!(w, "\\u{{{:x}}}", c as u32)?,
+                c => w.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Control characters other than newlines and tabs are invisible, or worse,
+/// in an editor, so they only go into literals as escapes.
+fn needs_escape(c: char) -> bool {
+    c.is_control() && !matches!(c, '\n' | '\t')
+}
+
+/// Which kind of literal updates write for new expected values, set with
+/// `EXPECT_LITERAL_STYLE`. Existing literals keep their kind whenever they can.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LiteralStyle {
+    /// `EXPECT_LITERAL_STYLE=minimal`: a normal literal when nothing needs
+    /// escaping, or else a raw one with as few hashes as possible
+    #[default]
+    Minimal,
+    /// `EXPECT_LITERAL_STYLE=raw`: always a raw literal
+    Raw,
+    /// `EXPECT_LITERAL_STYLE=escaped`: a normal literal, with escapes where needed
+    Escaped,
+}
+
+impl LiteralStyle {
+    /// Reads `EXPECT_LITERAL_STYLE`, defaulting to `minimal` when it is unset.
+    pub fn from_env() -> LiteralStyle {
+        match std::env::var("EXPECT_LITERAL_STYLE") {
+            Ok(value) => LiteralStyle::parse(&value).unwrap_or_else(|err| panic!("{}", err)),
+            Err(_) => LiteralStyle::default(),
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<LiteralStyle, String> {
+        match value {
+            "minimal" => Ok(LiteralStyle::Minimal),
+            "raw" => Ok(LiteralStyle::Raw),
+            "escaped" => Ok(LiteralStyle::Escaped),
+            _ => Err(format!(
+                "Unknown EXPECT_LITERAL_STYLE value: `{}`, expected `minimal`, `raw` or `escaped`",
+                value
+            )),
+        }
+    }
+}
+
+impl StrLitKind {
+    /// The kind of a new literal holding `s`. Control characters always need escapes.
+    pub fn for_new(s: &str, style: LiteralStyle) -> StrLitKind {
+        if s.chars().any(needs_escape) {
+            return StrLitKind::Escaped;
+        }
+        match style {
+            LiteralStyle::Minimal => StrLitKind::from(s),
+            LiteralStyle::Raw => StrLitKind::Raw(raw_hashes(s)),
+            LiteralStyle::Escaped if s.contains(['"', '\\', '\n']) => StrLitKind::Escaped,
+            LiteralStyle::Escaped => StrLitKind::Normal,
+        }
+    }
+
+    /// The kind of the literal written as `literal` in the source.
+    pub fn of_literal(literal: &str) -> Option<StrLitKind> {
+        if let Some(hashes) = literal.strip_prefix('r') {
+            Some(StrLitKind::Raw(
+                hashes.chars().take_while(|&c| c == '#').count(),
+            ))
+        } else if literal.starts_with('"') && literal.contains('\\') {
+            Some(StrLitKind::Escaped)
+        } else if literal.starts_with('"') {
+            Some(StrLitKind::Normal)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `s` can be written into this kind of literal.
+    pub fn can_hold(&self, s: &str) -> bool {
+        match self {
+            Self::Normal => matches!(StrLitKind::from(s), Self::Normal),
+            Self::Raw(n) => {
+                let terminator = format!("\"{}", "#".repeat(*n));
+                !s.chars().any(needs_escape) && !s.contains(&terminator)
+            }
+            Self::Escaped => true,
+        }
+    }
+}
+
+/// One more hash than ever follows a double quote in `s`, so the raw literal
+/// only ends where it should.
+fn raw_hashes(s: &str) -> usize {
+    s.split('"')
+        .skip(1)
+        .map(|s: &str| s.chars().take_while(|&c| c == '#').count() + 1)
+        .max()
+        .unwrap_or(1)
 }
 
 impl From<&str> for StrLitKind {
     fn from(s: &str) -> Self {
+        if s.chars().any(needs_escape) {
+            return StrLitKind::Escaped;
+        }
         let has_double_quote = s.chars().any(|c| c == '"');
         if has_double_quote {
-            // Find the maximum number of hashes that follow a double quote in the string.
-            // We need to use one more than that to delimit the string.
-            let max_hashes = s
-                .split('"')
-                .map(|s: &str| s.chars().take_while(|&c| c == '#').count())
-                .max()
-                .unwrap();
-            StrLitKind::Raw(max_hashes + 1)
+            StrLitKind::Raw(raw_hashes(s))
         } else {
             let has_backslash_or_newline = s.chars().any(|c| matches!(c, '\\' | '\n'));
             if has_backslash_or_newline {
@@ -0,0 +1,87 @@
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+/// What happened to the expects of a test process, printed when it exits.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Number of updated expects in every file that was written
+    pub updated: BTreeMap<&'static str, usize>,
+    /// Number of expects that failed in compare mode, by file
+    pub failed: BTreeMap<&'static str, usize>,
+    /// Number of expects with expected literals they never reached
+    pub surplus: usize,
+    /// Number of snapshots saved for `cargo expect review`
+    pub pending: usize,
+    /// Whether the updates were only printed as a diff
+    pub dry_run: bool,
+    /// The patch file written by `UPDATE_EXPECT=patch`
+    pub patch_file: Option<PathBuf>,
+}
+
+impl Summary {
+    pub fn is_empty(&self) -> bool {
+        self.updated.is_empty() && self.failed.is_empty() && self.surplus == 0 && self.pending == 0
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let updated = if self.dry_run {
+            "would be updated"
+        } else {
+            "updated"
+        };
+        write_counts(f, updated, &self.updated)?;
+        write_counts(f, "failed", &self.failed)?;
+        if self.surplus > 0 {
+            writeln!(
+                f,
+                "    {} with surplus expected literals, failing the test binary",
+                plural(self.surplus, "expect")
+            )?;
+        }
+        if let Some(patch_file) = &self.patch_file {
+            writeln!(
+                f,
+                "    patch written to {}, apply it with `git apply`",
+                patch_file.display()
+            )?;
+        }
+        if self.pending > 0 {
+            writeln!(
+                f,
+                "    {} pending, run `cargo expect review`",
+                plural(self.pending, "snapshot")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn write_counts(
+    f: &mut fmt::Formatter<'_>,
+    verb: &str,
+    counts: &BTreeMap<&'static str, usize>,
+) -> fmt::Result {
+    if counts.is_empty() {
+        return Ok(());
+    }
+    writeln!(
+        f,
+        "    {} {} in {}:",
+        plural(counts.values().sum(), "expect"),
+        verb,
+        plural(counts.len(), "file")
+    )?;
+    for (file, count) in counts {
+        writeln!(f, "        {} ({})", file, count)?;
+    }
+    Ok(())
+}
+
+pub fn plural(count: usize, word: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, word)
+    } else {
+        format!("{} {}s", count, word)
+    }
+}
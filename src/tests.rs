@@ -1,9 +1,24 @@
 use crate::{
+    atomic_write::write_atomically,
+    call_edit::CallEdit,
     expect,
-    expect::{Expect, FilePosition},
+    expect::{find_location, Expect, FilePosition},
+    expect_log::ExpectLog,
+    expect_unordered, expect_value,
+    journal::{rebase_edit, rebase_range, relocate_region, Edit, Journal, JournalEntry},
+    patch_file,
     patchwork::{PatchOrdering, Patchwork},
-    runtime::format_patch,
-    str_lit_kind::StrLitKind,
+    pending::PendingSnapshot,
+    runtime::{content_hash, format_patch, FileRuntime, Runtime, TEST_TARGET_DIR},
+    scope::{
+        describe_finished_tests, describe_mismatches, ExpectContext, ExpectScope, FinishedTest,
+        SiteCount,
+    },
+    source_style::SourceStyle,
+    str_lit_kind::{LiteralStyle, StrLitKind},
+    summary::Summary,
+    unified_diff::unified_diff,
+    update_mode::UpdateMode,
 };
 
 #[test]
@@ -154,16 +169,17 @@ fn test_lit_kind_for_patch_double_quote_triple_hash() {
 
 #[test]
 fn test_format_patch_multi_line() {
-    let patch = format_patch(0, "hello\nworld\n");
+    let value = "hello\nworld\n";
+    let patch = format_patch("    ", StrLitKind::from(value), value);
     expect!(&patch, r##""r#\"\n    hello\n    world\n    \"#""##);
 }
 
 #[test]
 
 fn test_format_patch_multi_line2() {
-    let desired_indent = 4;
+    let desired_indent = "        ";
     let patch = "struct Test {\n    field_a: u32,\n    field_b: f64,\n    field_c: String,\n}\n";
-    let patch = format_patch(desired_indent, patch);
+    let patch = format_patch(desired_indent, StrLitKind::from(patch), patch);
     expect!(
         patch,
         r##""r#\"\n        struct Test {\n            field_a: u32,\n            field_b: f64,\n            field_c: String,\n        }\n        \"#""##
@@ -172,10 +188,91 @@ fn test_format_patch_multi_line2() {
 
 #[test]
 fn test_format_patch_single_line() {
-    let patch = format_patch(0, "single line");
+    let value = "single line";
+    let patch = format_patch("    ", StrLitKind::from(value), value);
     expect!(&patch, r#""\"single line\"""#);
 }
 
+#[test]
+fn test_lit_kind_for_patch_control_characters() {
+    expect!(StrLitKind::from("\x1b[31mred\x1b[0m"), "Escaped");
+    expect!(StrLitKind::from("\"quoted\"\0"), "Escaped");
+    expect!(
+        StrLitKind::from("tab\tseparated\n"),
+        r#"
+        Raw(
+            1,
+        )"#
+    );
+}
+
+#[test]
+fn test_format_patch_escaped() {
+    let value = "\x1b[1mbold\x1b[0m \"quoted\" \\ \0\r\t\x7f\u{9b}";
+    let patch = format_patch("    ", StrLitKind::from(value), value);
+    expect!(
+        &patch,
+        r#""\"\\x1b[1mbold\\x1b[0m \\\"quoted\\\" \\\\ \\0\\r\\t\\x7f\\u{9b}\"""#
+    );
+    expect!(
+        syn::parse_str::<syn::LitStr>(&patch).unwrap().value() == value,
+        "true"
+    );
+    let value = "\x1b[31mred\x1b[0m\n\tgreen\n";
+    expect!(
+        format_patch("    ", StrLitKind::from(value), value),
+        r#""\"\n    \\x1b[31mred\\x1b[0m\n    \\tgreen\n    \"""#
+    );
+}
+
+#[test]
+fn test_escaped_literal_round_trip() {
+    let actual = "a\r\nb\r\nc\r\n";
+    let file = "fn f() {\n    expect!(x, \"\");\n}\n";
+    let mut call = CallEdit::new(find_location(file, 2, 5, true, 1).unwrap());
+    call.set(0, None, actual);
+    let mut patchwork = Patchwork::new(file.to_string());
+    call.apply(file, &SourceStyle::from_config("", file), &mut patchwork);
+    let file = patchwork.text();
+    let location = find_location(file, 2, 5, true, 1).unwrap();
+    let literal = &file[location.expected_ranges[0].clone()];
+    expect!(
+        literal,
+        r#""\"\n        a\\r\n        b\\r\n        c\\r\n        \"""#
+    );
+    let value = syn::parse_str::<syn::LitStr>(literal).unwrap().value();
+    let expect = Expect {
+        file_position: FilePosition {
+            file: "src/escaped.rs",
+            line: 2,
+            column: 5,
+        },
+        raw_actual: Some("x"),
+        expected: [Box::leak(value.into_boxed_str())],
+        raw_expected: [Box::leak(literal.to_string().into_boxed_str())],
+        assertion_index: 0,
+        keys: None,
+        spans: None,
+    };
+    expect.assert_eq(actual);
+}
+
+#[test]
+fn test_escaped_literal() {
+    expect!(
+        "\x1b[31mred\x1b[0m\n  plain\n",
+        "\n    \x1b[31mred\x1b[0m\n      plain\n    "
+    );
+    // Whitespace control characters aren't taken for indentation
+    expect!("\x0bfirst\n\x0csecond", "\n    \x0bfirst\n    \x0csecond");
+    let file = "fn f() {\n    expect!(x, \"\\x1b[0m\\u{9b}\");\n}\n";
+    let location = find_location(file, 2, 5, true, 1).unwrap();
+    expect!(
+        &file[location.expected_ranges[0].clone()],
+        r#""\"\\x1b[0m\\u{9b}\"""#
+    );
+}
+
 #[test]
 fn test_patchwork() {
     let mut patchwork = Patchwork::new("one two three".to_string());
@@ -214,6 +311,200 @@ fn test_patchwork() {
     );
 }
 
+#[test]
+fn test_patchwork_edits() {
+    let mut patchwork = Patchwork::new("one two three".to_string());
+    patchwork.patch_range(4..7, "zwei", PatchOrdering::Normal);
+    patchwork.patch_range(0..3, "один", PatchOrdering::Normal);
+    patchwork.patch_insert(13, "333", PatchOrdering::Normal);
+    expect!(
+        patchwork.edits(),
+        r#"
+        [
+            (
+                0..3,
+                "один",
+            ),
+            (
+                4..7,
+                "zwei",
+            ),
+            (
+                13..13,
+                "333",
+            ),
+        ]"#
+    );
+}
+
+#[test]
+fn test_rebase_range() {
+    let edits = [
+        Edit {
+            range: 0..3,
+            inserted_len: 8,
+        },
+        Edit {
+            range: 20..25,
+            inserted_len: 0,
+        },
+    ];
+    expect!(
+        rebase_range(&(4..7), &edits),
+        r#"
+        Some(
+            9..12,
+        )"#
+    );
+    expect!(
+        rebase_range(&(3..3), &edits),
+        r#"
+        Some(
+            8..8,
+        )"#
+    );
+    expect!(
+        rebase_range(&(30..31), &edits),
+        r#"
+        Some(
+            30..31,
+        )"#
+    );
+    expect!(rebase_range(&(2..5), &edits), "None");
+    expect!(rebase_range(&(22..22), &edits), "None");
+}
+
+#[test]
+fn test_rebase_edit() {
+    // "one two" -> "один two" -> "один two three"
+    let entries = [
+        JournalEntry {
+            from_hash: 1,
+            to_hash: 2,
+            edits: vec![Edit {
+                range: 0..3,
+                inserted_len: "один".len(),
+            }],
+        },
+        JournalEntry {
+            from_hash: 2,
+            to_hash: 3,
+            edits: vec![Edit {
+                range: 12..12,
+                inserted_len: 6,
+            }],
+        },
+    ];
+    let current = "один two three";
+    expect!(
+        rebase_edit(&(4..7), "zwei", &entries, current),
+        r#"
+        Moved(
+            9..12,
+        )"#
+    );
+    expect!(
+        rebase_edit(&(0..3), "один", &entries, current),
+        "AlreadyMade"
+    );
+    expect!(rebase_edit(&(0..3), "eins", &entries, current), "Conflict");
+    expect!(rebase_edit(&(1..2), "N", &entries, current), "Conflict");
+}
+
+#[test]
+fn test_relocate_region() {
+    let original = "a();\nexpect!(x, \"\");\nb();\nexpect!(x, \"\");\n";
+    let second = 26..41;
+    expect!(&original[second.clone()], r#""expect!(x, \"\");""#);
+    expect!(
+        relocate_region(original, &second, &format!("// new\n{original}")),
+        r#"
+        Some(
+            33,
+        )"#
+    );
+    expect!(
+        relocate_region(original, &second, &original.replace("a();\n", "")),
+        r#"
+        Some(
+            21,
+        )"#
+    );
+    expect!(
+        relocate_region(
+            original,
+            &second,
+            &original.replace("b();", "expect!(x, \"\");")
+        ),
+        "None"
+    );
+    expect!(relocate_region(original, &second, "a();\n"), "None");
+}
+
+#[test]
+fn test_content_hash() {
+    // Stored on disk, so it must not change between Rust versions
+    let hash = |text| format!("{:016x}", content_hash(text));
+    expect!(hash(""), r#""cbf29ce484222325""#);
+    expect!(hash("a"), r#""af63dc4c8601ec8c""#);
+    expect!(hash("foobar"), r#""85944171f73967e8""#);
+}
+
+#[test]
+fn test_journal_round_trip() {
+    let entry = |from_hash, to_hash| JournalEntry {
+        from_hash,
+        to_hash,
+        edits: vec![Edit {
+            range: 4..7,
+            inserted_len: 2,
+        }],
+    };
+    let journal = Journal {
+        created: std::time::Duration::new(1700000000, 5),
+        base_text: "fn main() {}\n".to_string(),
+        entries: vec![entry(1, 2), entry(2, 3), entry(7, 8)],
+    };
+    let serialized = journal.serialize();
+    expect!(
+        &serialized,
+        r#""created 1700000000 5\nbase 13\nfn main() {}\n\nedit 1 2 1\n4 7 2\nedit 2 3 1\n4 7 2\nedit 7 8 1\n4 7 2\n""#
+    );
+    expect!(Journal::parse(&serialized) == Some(journal.clone()), "true");
+    expect!(
+        Journal::parse("created 1 2\nbase 40\nshort\n").is_none(),
+        "true"
+    );
+
+    let hashes = |entries: Option<&[JournalEntry]>| {
+        entries.map(|entries| {
+            entries
+                .iter()
+                .map(|entry| entry.to_hash)
+                .collect::<Vec<_>>()
+        })
+    };
+    expect!(
+        hashes(journal.entries_between(1, 3)),
+        r#"
+        Some(
+            [
+                2,
+                3,
+            ],
+        )"#
+    );
+    expect!(
+        hashes(journal.entries_between(2, 2)),
+        r#"
+        Some(
+            [],
+        )"#
+    );
+    expect!(hashes(journal.entries_between(1, 8)), "None");
+    expect!(hashes(journal.entries_between(3, 1)), "None");
+}
+
 #[test]
 pub fn test_multi_expect() {
     for i in 0..2 {
@@ -236,6 +527,39 @@ pub fn test_multi_expect() {
     }
 }
 
+#[test]
+pub fn test_keyed_expect() {
+    for (name, value) in [("b", 2), ("a", 1), ("c", 3)] {
+        expect!(name => value, "b" => "2", "a" => "1", "c" => "3");
+    }
+}
+
+#[test]
+pub fn test_unordered_expect() {
+    for value in [3, 1, 2, 1] {
+        expect_unordered!(value, "1", "1", "2", "3");
+    }
+}
+
+#[test]
+pub fn test_expect_log() {
+    let log = ExpectLog::new(expect_value!(
+        r#"
+        from a thread
+        call 0
+        call 1
+        call 2
+        "#
+    ));
+    std::thread::scope(|scope| {
+        scope.spawn(|| log.push("from a thread"));
+    });
+    for i in 0..3 {
+        log.push(format_args!("call {}", i));
+    }
+    log.finish();
+}
+
 #[test]
 pub fn test_patch_ordering() {
     let mut patchwork = Patchwork::new("one two three".to_string());
@@ -254,24 +578,31 @@ pub fn test_find_expect_location() {
             line: 7,
             column: 5,
         },
-        raw_actual: "StrLitKind::from(\"\")",
+        raw_actual: Some("StrLitKind::from(\"\")"),
         expected: ["ABC", "DEF"],
         raw_expected: ["\"ABC\"", "\"DEF\""],
         assertion_index: 0,
+        keys: None,
+        spans: None,
     };
     let file = "use crate::str_lit_kind::StrLitKind;\n\nuse super::*;\n\n#[test]\nfn test_lit_kind_for_patch_empty() {\n    expect!(StrLitKind::from(\"\"), \"ABC\", \"DEF\");\n}\n";
-    let location = expect.find_expect_location(file);
+    let location = expect.find_expect_location(file).unwrap();
     expect!(
         location,
         r#"
         ExpectLocation {
-            line_indent: 4,
+            line_indent: "    ",
+            actual_range: Some(
+                110..130,
+            ),
             expected_ranges: [
                 132..137,
                 139..144,
             ],
+            key_ranges: [],
             start_index: 110,
             end_index: 144,
+            body_range: 110..144,
         }"#
     );
 }
@@ -283,28 +614,385 @@ pub fn test_find_expect_location_stringify() {
             line: 5,
             column: 5,
         },
-        raw_actual: "stringify!(struct Test { test : u32, })",
+        raw_actual: Some("stringify!(struct Test { test : u32, })"),
         expected: ["test", "test2"],
         raw_expected: ["\"test\"", "\"test2\""],
         assertion_index: 0,
+        keys: None,
+        spans: None,
     };
     let file = "use super::*;\n\n#[test]\nfn test_stringify() {\n    expect!(\n        stringify!(\n            struct Test {\n                test: u32,\n            }\n        ),\n        \"test\",\n        \"test2\"\n    );\n}\n";
-    let location = expect.find_expect_location(file);
+    let location = expect.find_expect_location(file).unwrap();
     expect!(
         location,
         r#"
         ExpectLocation {
-            line_indent: 4,
+            line_indent: "    ",
+            actual_range: Some(
+                66..154,
+            ),
             expected_ranges: [
                 164..170,
                 180..187,
             ],
+            key_ranges: [],
             start_index: 66,
             end_index: 187,
+            body_range: 57..192,
         }"#
     );
 }
 
+#[test]
+pub fn test_find_keyed_expect_location() {
+    let expect = Expect {
+        file_position: FilePosition {
+            file: "src/tests4.rs",
+            line: 3,
+            column: 9,
+        },
+        raw_actual: Some("value"),
+        expected: ["1", "2"],
+        raw_expected: ["\"1\"", "\"2\""],
+        assertion_index: 0,
+        keys: Some(["a", "b"]),
+        spans: None,
+    };
+    let file = "fn test_keyed() {\n    for (name, value) in values() {\n        expect!(name => value, \"a\" => \"1\", \"b\" => \"2\");\n    }\n}\n";
+    let location = expect.find_expect_location(file).unwrap();
+    expect!(
+        &location,
+        r#"
+        ExpectLocation {
+            line_indent: "        ",
+            actual_range: Some(
+                78..83,
+            ),
+            expected_ranges: [
+                92..95,
+                104..107,
+            ],
+            key_ranges: [
+                85..92,
+                97..104,
+            ],
+            start_index: 70,
+            end_index: 107,
+            body_range: 70..107,
+        }"#
+    );
+    let mut call = CallEdit::new(location);
+    call.set(1, Some("b"), "20");
+    call.set(2, Some("c"), "3");
+    call.set(3, Some("d"), "4\n5");
+    let mut patchwork = Patchwork::new(file.to_string());
+    call.apply(file, &SourceStyle::default(), &mut patchwork);
+    expect!(
+        patchwork.text(),
+        r##""fn test_keyed() {\n    for (name, value) in values() {\n        expect!(\n            name => value,\n            \"a\" => \"1\",\n            \"b\" => \"20\",\n            \"c\" => \"3\",\n            \"d\" => r#\"\n            4\n            5\"#\n        );\n    }\n}\n""##
+    );
+    let error = expect
+        .find_expect_location(&file.replace("\"a\" =>", ""))
+        .unwrap_err();
+    expect!(
+        error.to_string(),
+        r#""macro argument 1 is keyed differently from the first argument""#
+    );
+}
+
+#[test]
+pub fn test_call_edit_layout() {
+    let check = |file: &str, num_expected: usize, literals: &[&str], expected: Expect<1>| {
+        let location = find_location(file, 2, 5, true, num_expected).unwrap();
+        let mut call = CallEdit::new(location);
+        for (index, literal) in literals.iter().enumerate() {
+            call.set(index, None, literal);
+        }
+        call.truncate(literals.len());
+        let mut patchwork = Patchwork::new(file.to_string());
+        call.apply(file, &SourceStyle::default(), &mut patchwork);
+        expected.assert_eq(patchwork.text());
+    };
+    // A literal turning multiline puts every argument on its own line
+    check(
+        "fn f() {\n    expect!(x, \"ABC\");\n}\n",
+        1,
+        &["ABC\nDEF"],
+        expect_value!(
+            r##"
+            fn f() {
+                expect!(
+                    x,
+                    r#"
+                    ABC
+                    DEF"#
+                );
+            }
+            "##
+        ),
+    );
+    // And the last multiline literal going away puts them back on one line
+    check(
+        "fn f() {\n    expect!(\n        x,\n        r#\"\n        ABC\n        DEF\"#,\n    );\n}\n",
+        1,
+        &["ABC", "DEF"],
+        expect_value!(
+            r##"
+            fn f() {
+                expect!(x, r#"ABC"#, "DEF");
+            }
+            "##
+        ),
+    );
+    // Short arguments share a single line
+    check(
+        "fn f() {\n    expect!(\n        x,\n        \"ABC\",\n        \"DEF\",\n    );\n}\n",
+        2,
+        &["ABC"],
+        expect_value!(
+            r#"
+            fn f() {
+                expect!(x, "ABC");
+            }
+            "#
+        ),
+    );
+    // Mixing single-line and multiline arguments puts every one on its own line
+    check(
+        "fn f() {\n    expect!(x, \"ABC\",\n        \"DEF\");\n}\n",
+        2,
+        &["ABC", "DEF\nGHI"],
+        expect_value!(
+            r##"
+            fn f() {
+                expect!(
+                    x,
+                    "ABC",
+                    r#"
+                    DEF
+                    GHI"#
+                );
+            }
+            "##
+        ),
+    );
+    // And keeps them there
+    check(
+        "fn f() {\n    expect!(\n        x,\n        \"ABC\",\n        r#\"\n        DEF\"#\n    );\n}\n",
+        2,
+        &["ABC", "DEF\nGHI", "JKL"],
+        expect_value!(
+            r##"
+            fn f() {
+                expect!(
+                    x,
+                    "ABC",
+                    r#"
+                    DEF
+                    GHI"#,
+                    "JKL"
+                );
+            }
+            "##
+        ),
+    );
+    // Appending past the maximum width also puts every argument on its own line
+    let long = "0123456789".repeat(8);
+    check(
+        "fn f() {\n    expect!(x, \"ABC\");\n}\n",
+        1,
+        &["ABC", &long],
+        expect_value!(
+            r#"
+            fn f() {
+                expect!(
+                    x,
+                    "ABC",
+                    "01234567890123456789012345678901234567890123456789012345678901234567890123456789"
+                );
+            }
+            "#
+        ),
+    );
+    // Comments between the arguments are never laid out again
+    check(
+        "fn f() {\n    expect!(x, /* old */ \"ABC\");\n}\n",
+        1,
+        &["ABC\nDEF"],
+        expect_value!(
+            r##"
+            fn f() {
+                expect!(x, /* old */ r#"
+                    ABC
+                    DEF"#);
+            }
+            "##
+        ),
+    );
+}
+
+#[test]
+pub fn test_call_edit_tabs() {
+    let file = "fn f() {\n\tif x {\n\t\texpect!(x, \"ABC\");\n\t}\n}\n";
+    let location = find_location(file, 3, 3, true, 1).unwrap();
+    let mut call = CallEdit::new(location);
+    call.set(0, None, "ABC\n    DEF\n");
+    let mut patchwork = Patchwork::new(file.to_string());
+    call.apply(file, &SourceStyle::from_config("", file), &mut patchwork);
+    expect!(
+        patchwork.text(),
+        r##""fn f() {\n\tif x {\n\t\texpect!(\n\t\t\tx,\n\t\t\tr#\"\n\t\t\tABC\n\t\t\t    DEF\n\t\t\t\"#\n\t\t);\n\t}\n}\n""##
+    );
+}
+
+#[test]
+pub fn test_call_edit_crlf() {
+    let update = |file: &str, literal: &str| {
+        let location = find_location(file, 2, 5, true, 1).unwrap();
+        let mut call = CallEdit::new(location);
+        call.set(0, None, literal);
+        call.truncate(1);
+        let mut patchwork = Patchwork::new(file.to_string());
+        call.apply(file, &SourceStyle::from_config("", file), &mut patchwork);
+        patchwork.text().to_string()
+    };
+    let file = update(
+        "fn f() {\r\n    expect!(x, \"ABC\");\r\n}\r\n",
+        "ABC\nDEF\n",
+    );
+    expect!(
+        &file,
+        r##""fn f() {\r\n    expect!(\r\n        x,\r\n        r#\"\r\n        ABC\r\n        DEF\r\n        \"#\r\n    );\r\n}\r\n""##
+    );
+    expect!(
+        update(&file, "ABC"),
+        r##""fn f() {\r\n    expect!(x, r#\"ABC\"#);\r\n}\r\n""##
+    );
+    // Escaped CRs are part of the expected value
+    expect!("first\r\nsecond\r\n", "first\r\nsecond\r\n");
+    expect!("first\r\nsecond", "\n    first\r\n    second");
+    // Real ones come from a file with CRLF line endings
+    let literal = "\r\n    first\r\n    second\r\n    ";
+    let expect = Expect {
+        file_position: FilePosition {
+            file: "src/crlf.rs",
+            line: 2,
+            column: 5,
+        },
+        raw_actual: Some("x"),
+        expected: [literal],
+        raw_expected: ["r#\"\r\n    first\r\n    second\r\n    \"#"],
+        assertion_index: 0,
+        keys: None,
+        spans: None,
+    };
+    expect.assert_eq("first\nsecond\n");
+}
+
+#[test]
+pub fn test_call_edit_literal_kinds() {
+    let check = |literal: &str, value: &str, literals: LiteralStyle, expected: Expect<1>| {
+        let file = format!("fn f() {{\n    expect!(x, {});\n}}\n", literal);
+        let location = find_location(&file, 2, 5, true, 1).unwrap();
+        let mut call = CallEdit::new(location);
+        call.set(0, None, value);
+        call.set(1, None, value);
+        let style = SourceStyle {
+            literals,
+            ..SourceStyle::default()
+        };
+        let mut patchwork = Patchwork::new(file.clone());
+        call.apply(&file, &style, &mut patchwork);
+        expected.assert_eq(patchwork.text().lines().nth(1).unwrap().trim());
+    };
+    // Existing literals keep their kind, and new ones follow the preference
+    check(
+        r###"r##"old"##"###,
+        "new",
+        LiteralStyle::Minimal,
+        expect_value!(r###"expect!(x, r##"new"##, "new");"###),
+    );
+    check(
+        r#""old""#,
+        "say \"hi\"",
+        LiteralStyle::Minimal,
+        expect_value!(r##"expect!(x, r#"say "hi""#, r#"say "hi""#);"##),
+    );
+    check(
+        r#""old\t""#,
+        "say \"hi\"",
+        LiteralStyle::Raw,
+        expect_value!(r##"expect!(x, "say \"hi\"", r#"say "hi""#);"##),
+    );
+    check(
+        r#""old""#,
+        "new",
+        LiteralStyle::Raw,
+        expect_value!(r##"expect!(x, "new", r#"new"#);"##),
+    );
+    check(
+        r#""old""#,
+        "C:\\",
+        LiteralStyle::Escaped,
+        expect_value!(r#"expect!(x, "C:\\", "C:\\");"#),
+    );
+    // Unless the new value doesn't fit
+    check(
+        r#"r"old""#,
+        "say \"hi\"",
+        LiteralStyle::Minimal,
+        expect_value!(r##"expect!(x, r#"say "hi""#, r#"say "hi""#);"##),
+    );
+    check(
+        r##"r#"old"#"##,
+        "\x1b[0m",
+        LiteralStyle::Raw,
+        expect_value!(r#"expect!(x, "\x1b[0m", "\x1b[0m");"#),
+    );
+    expect!(LiteralStyle::parse("raw"), "Ok(\n    Raw,\n)");
+    expect!(
+        LiteralStyle::parse("hashes"),
+        r#"
+        Err(
+            "Unknown EXPECT_LITERAL_STYLE value: `hashes`, expected `minimal`, `raw` or `escaped`",
+        )"#
+    );
+}
+
+#[test]
+pub fn test_source_style() {
+    let indent = |config: &str, text: &str| SourceStyle::from_config(config, text).indent;
+    let tabs = "fn f() {\n\tif x {\n\t\ty();\n\t}\n}\n";
+    let two_spaces = "fn f() {\n  if x {\n    y();\n  }\n}\n";
+    expect!(indent("", tabs), r#""\t""#);
+    expect!(indent("", two_spaces), r#""  ""#);
+    expect!(indent("", ""), r#""    ""#);
+    expect!(
+        indent("hard_tabs = true # indent with tabs", two_spaces),
+        r#""\t""#
+    );
+    expect!(indent("hard_tabs = false", tabs), r#""    ""#);
+    expect!(indent("max_width = 80\ntab_spaces = 2", ""), r#""  ""#);
+    let style = SourceStyle::from_config("max_width = 80\ntab_spaces = 2", "");
+    expect!(style.max_width, "80");
+    expect!(style.width("\t\tcode"), "8");
+    let newline = |config: &str, text: &str| SourceStyle::from_config(config, text).newline;
+    expect!(newline("", "fn f() {\r\n}\r\n"), r#""\r\n""#);
+    expect!(newline("", "fn f() {\n}\r\n"), r#""\n""#);
+    expect!(newline("newline_style = \"Windows\"", ""), r#""\r\n""#);
+    expect!(
+        newline("newline_style = \"Unix\"", "fn f() {\r\n}\r\n"),
+        r#""\n""#
+    );
+}
+
+#[test]
+pub fn test_tab_indented_literal() {
+    expect!("first\n    second\n", "\n\t\tfirst\n\t\t    second\n\t\t");
+    // Tabs and spaces are never taken for one another
+    expect!("\tfirst\n    second", "\n\tfirst\n    second");
+}
+
 #[test]
 pub fn test_fibonacci() {
     fn fibonacci(x: usize) -> usize {
@@ -398,3 +1086,667 @@ pub fn test_expect_layered_tuple() {
         )"#
     );
 }
+
+#[test]
+pub fn test_expect_value_check_helper() {
+    fn check<const N: usize>(input: &str, expect: Expect<N>) {
+        let reversed = input.chars().rev().collect::<String>();
+        expect.assert_eq(&reversed);
+    }
+    check("abc", expect_value!("cba"));
+    check("racecar", expect_value!("racecar"));
+}
+
+#[test]
+pub fn test_expect_value_debug_check_helper() {
+    fn check<const N: usize>(input: &str, expect: Expect<N>) {
+        expect.assert_debug_eq(input.split(',').collect::<Vec<_>>());
+    }
+    check(
+        "a,b",
+        expect_value!(
+            r#"
+            [
+                "a",
+                "b",
+            ]"#
+        ),
+    );
+}
+
+#[test]
+pub fn test_find_expect_value_location() {
+    let expect = Expect {
+        file_position: FilePosition {
+            file: "src/tests4.rs",
+            line: 3,
+            column: 15,
+        },
+        raw_actual: None,
+        expected: ["ABC"],
+        raw_expected: ["\"ABC\""],
+        assertion_index: 0,
+        keys: None,
+        spans: None,
+    };
+    let file = "#[test]\nfn test_check() {\n    check(\"\", expect_value!(\"ABC\"));\n}\n";
+    let location = expect.find_expect_location(file).unwrap();
+    expect!(
+        location,
+        r#"
+        ExpectLocation {
+            line_indent: "    ",
+            actual_range: None,
+            expected_ranges: [
+                54..59,
+            ],
+            key_ranges: [],
+            start_index: 54,
+            end_index: 59,
+            body_range: 54..59,
+        }"#
+    );
+}
+
+#[test]
+pub fn test_find_expect_location_expected_inside_actual() {
+    let expect = Expect {
+        file_position: FilePosition {
+            file: "src/tests5.rs",
+            line: 2,
+            column: 5,
+        },
+        raw_actual: Some("f(\"ABC\")"),
+        expected: ["ABC"],
+        raw_expected: ["\"ABC\""],
+        assertion_index: 0,
+        keys: None,
+        spans: None,
+    };
+    let file = "fn test() {\n    expect!(f(\"ABC\"), \"ABC\");\n}\n";
+    let location = expect.find_expect_location(file).unwrap();
+    expect!(
+        location,
+        r#"
+        ExpectLocation {
+            line_indent: "    ",
+            actual_range: Some(
+                24..32,
+            ),
+            expected_ranges: [
+                34..39,
+            ],
+            key_ranges: [],
+            start_index: 24,
+            end_index: 39,
+            body_range: 24..39,
+        }"#
+    );
+}
+
+#[test]
+pub fn test_find_expect_location_comments_and_delimiters() {
+    let expect = Expect {
+        file_position: FilePosition {
+            file: "src/tests6.rs",
+            line: 3,
+            column: 5,
+        },
+        raw_actual: Some("x"),
+        expected: ["1", "2"],
+        raw_expected: ["\"1\"", "\"2\""],
+        assertion_index: 0,
+        keys: None,
+        spans: None,
+    };
+    let file = "fn test() {\n    expect![x, \"1\"];\n    expect! {\n        x, // \"1\", \"2\"\n        /* \"1\" */ \"1\",\n        \"2\",\n    };\n}\n";
+    let location = expect.find_expect_location(file).unwrap();
+    expect!(
+        location,
+        r#"
+        ExpectLocation {
+            line_indent: "    ",
+            actual_range: Some(
+                55..56,
+            ),
+            expected_ranges: [
+                88..91,
+                101..104,
+            ],
+            key_ranges: [],
+            start_index: 55,
+            end_index: 104,
+            body_range: 46..110,
+        }"#
+    );
+}
+
+#[test]
+pub fn test_find_expect_location_errors() {
+    let expect = Expect {
+        file_position: FilePosition {
+            file: "src/tests7.rs",
+            line: 2,
+            column: 5,
+        },
+        raw_actual: Some("x"),
+        expected: ["1"],
+        raw_expected: ["\"1\""],
+        assertion_index: 0,
+        keys: None,
+        spans: None,
+    };
+    let error = expect
+        .find_expect_location("fn test() {\n    expect!(x, \"1\", \"2\");\n}\n")
+        .unwrap_err();
+    expect!(
+        error.to_string(),
+        r#""expected 2 macro arguments but found 3""#
+    );
+    let error = expect
+        .find_expect_location("fn test() {\n    expect!(x, y);\n}\n")
+        .unwrap_err();
+    expect!(
+        error.to_string(),
+        r#""macro argument 1 is not a string literal""#
+    );
+    let error = expect
+        .find_expect_location("fn test() {\n  expect!(x, \"1\");\n}\n")
+        .unwrap_err();
+    expect!(error.to_string(), r#""no macro invocation starts at 2:5""#);
+}
+
+#[test]
+pub fn test_update_mode_parse() {
+    expect!(
+        UpdateMode::parse("1"),
+        r#"
+        Ok(
+            All,
+        )"#
+    );
+    expect!(
+        UpdateMode::parse("all"),
+        r#"
+        Ok(
+            All,
+        )"#
+    );
+    expect!(
+        UpdateMode::parse("new"),
+        r#"
+        Ok(
+            New,
+        )"#
+    );
+    expect!(
+        UpdateMode::parse("patch"),
+        r#"
+        Ok(
+            Patch,
+        )"#
+    );
+    expect!(
+        UpdateMode::parse("dry-run"),
+        r#"
+        Ok(
+            DryRun,
+        )"#
+    );
+    expect!(UpdateMode::parse("yes").is_err(), "true");
+}
+
+#[test]
+pub fn test_update_mode_allows_update() {
+    expect!(UpdateMode::All.allows_update(true), "true");
+    expect!(UpdateMode::New.allows_update(true), "false");
+    expect!(UpdateMode::New.allows_update(false), "true");
+}
+
+#[test]
+pub fn test_pending_snapshot_round_trip() {
+    let snapshot = PendingSnapshot {
+        file: "/workspace/src/lib.rs".into(),
+        file_hash: 42,
+        line: 7,
+        column: 5,
+        has_actual: true,
+        num_expected: 1,
+        assertion_index: 0,
+        key: None,
+        test_name: "tests::test_round_trip".to_string(),
+        old: "line one\nold 3\n".to_string(),
+        new: "new 12\n\"quoted\"".to_string(),
+    };
+    let serialized = snapshot.serialize();
+    expect!(
+        &serialized,
+        r##"
+        "file /workspace/src/lib.rs\nfile_hash 42\nline 7\ncolumn 5\nhas_actual true\nnum_expected 1\nassertion_index 0\ntest_name tests::test_round_trip\nold 15\nline one\nold 3\n\nnew 15\nnew 12\n\"quoted\"\n""##
+    );
+    let keyed = PendingSnapshot {
+        key: Some("key\n2".to_string()),
+        ..snapshot.clone()
+    };
+    expect!(
+        PendingSnapshot::parse(&keyed.serialize()) == Some(keyed),
+        "true"
+    );
+    expect!(
+        PendingSnapshot::parse(&serialized) == Some(snapshot),
+        "true"
+    );
+    expect!(
+        PendingSnapshot::parse("file /src/lib.rs\n").is_none(),
+        "true"
+    );
+}
+
+/// A temporary directory with a source file to update, which also holds the
+/// target directory that expect-tests writes its own files to. Everything is
+/// removed once the fixture is dropped.
+struct Fixture {
+    dir: std::path::PathBuf,
+    path: std::path::PathBuf,
+    file: &'static str,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Fixture {
+        let dir =
+            std::env::temp_dir().join(format!("expect-tests-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TEST_TARGET_DIR.set(Some(dir.join("target")));
+        let path = dir.join("source.rs");
+        let file = Box::leak(path.to_string_lossy().into_owned().into_boxed_str());
+        Fixture { dir, path, file }
+    }
+
+    fn write(&self, source: &str) {
+        std::fs::write(&self.path, source).unwrap();
+        // Pretend the test binary was built after the source was written
+        let source_file = std::fs::File::options()
+            .write(true)
+            .open(&self.path)
+            .unwrap();
+        source_file.set_modified(std::time::UNIX_EPOCH).unwrap();
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        TEST_TARGET_DIR.set(None);
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+pub fn test_patch_mode_twice() {
+    let fixture = Fixture::new("patch");
+    let expect = Expect {
+        file_position: FilePosition {
+            file: fixture.file,
+            line: 2,
+            column: 5,
+        },
+        raw_actual: Some("value"),
+        expected: ["old"],
+        raw_expected: ["\"old\""],
+        assertion_index: 0,
+        keys: None,
+        spans: None,
+    };
+    let shadow = patch_file::shadow_path(&fixture.path);
+    let run = |source: &str, actual: &str| {
+        fixture.write(source);
+        let mut runtime = FileRuntime::new(fixture.file, UpdateMode::Patch);
+        runtime.update(&expect, None, actual);
+        runtime.flush(false).unwrap();
+        std::fs::read_to_string(&shadow).unwrap()
+    };
+
+    let first = run("fn f() {\n    expect!(value, \"old\");\n}\n", "new");
+    expect!(&first, r#""fn f() {\n    expect!(value, \"new\");\n}\n""#);
+    // Applying the patch leaves the shadow based on the old source
+    let second = run(&first.replace("value", "other"), "newer");
+    expect!(
+        &second,
+        r#""fn f() {\n    expect!(other, \"newer\");\n}\n""#
+    );
+}
+
+#[test]
+pub fn test_find_surplus() {
+    let fixture = Fixture::new("surplus");
+    fixture.write(
+        "fn f() {\n    for i in 0..2 {\n        expect!(i, \"0\", \"1\", \"2\");\n    }\n}\n",
+    );
+    let file = fixture.file;
+    let run_twice = || {
+        let mut runtime = Runtime::default();
+        for assertion_index in 0..2 {
+            let expect = Expect {
+                file_position: FilePosition {
+                    file,
+                    line: 3,
+                    column: 9,
+                },
+                raw_actual: Some("i"),
+                expected: ["0", "1", "2"],
+                raw_expected: ["\"0\"", "\"1\"", "\"2\""],
+                assertion_index,
+                keys: None,
+                spans: None,
+            };
+            runtime.count_call(&expect, None);
+        }
+        runtime
+    };
+
+    let mut runtime = run_twice();
+    expect!(runtime.find_surplus(None), "1");
+    expect!(runtime.failures[file], "1");
+    expect!(runtime.per_file.is_empty(), "true");
+
+    // An `ExpectScope` already failed its test with it
+    let mut runtime = run_twice();
+    runtime.reported.insert(FilePosition {
+        file,
+        line: 3,
+        column: 9,
+    });
+    expect!(runtime.find_surplus(None), "0");
+    expect!(runtime.failures.is_empty(), "true");
+
+    let mut runtime = run_twice();
+    expect!(runtime.find_surplus(Some(UpdateMode::All)), "0");
+    expect!(runtime.failures.is_empty(), "true");
+    runtime.per_file[file].flush(false).unwrap();
+    expect!(
+        std::fs::read_to_string(&fixture.path).unwrap(),
+        r#""fn f() {\n    for i in 0..2 {\n        expect!(i, \"0\", \"1\");\n    }\n}\n""#
+    );
+}
+
+#[test]
+pub fn test_write_atomically() {
+    let fixture = Fixture::new("atomic");
+    let (dir, path) = (fixture.dir.clone(), fixture.path.clone());
+    std::fs::write(&path, "old contents").unwrap();
+    write_atomically(&path, "new contents").unwrap();
+    expect!(std::fs::read_to_string(&path).unwrap(), r#""new contents""#);
+    expect!(std::fs::read_dir(&dir).unwrap().count(), "1");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let link = dir.join("link.rs");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        write_atomically(&link, "through link").unwrap();
+        expect!(std::fs::read_to_string(&path).unwrap(), r#""through link""#);
+        expect!(
+            std::fs::symlink_metadata(&link).unwrap().is_symlink(),
+            "true"
+        );
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        expect!(format!("{:o}", mode & 0o777), r#""640""#);
+    }
+}
+
+#[test]
+pub fn test_summary() {
+    let mut summary = Summary::default();
+    expect!(summary.is_empty(), "true");
+    summary.updated.insert("src/tests.rs", 3);
+    summary.updated.insert("src/lib.rs", 1);
+    summary.failed.insert("tests/integration.rs", 1);
+    summary.surplus = 1;
+    summary.pending = 2;
+    summary.patch_file = Some("target/expect-tests/updates.patch".into());
+    expect!(
+        summary.to_string(),
+        r#""    4 expects updated in 2 files:\n        src/lib.rs (1)\n        src/tests.rs (3)\n    1 expect failed in 1 file:\n        tests/integration.rs (1)\n    1 expect with surplus expected literals, failing the test binary\n    patch written to target/expect-tests/updates.patch, apply it with `git apply`\n    2 snapshots pending, run `cargo expect review`\n""#
+    );
+}
+
+#[test]
+pub fn test_unified_diff() {
+    let check = |old: &str, new: &str, expected: Expect<1>| {
+        expected.assert_eq(&unified_diff("src/lib.rs", old, new));
+    };
+    let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n";
+    check(old, old, expect_value!(""));
+    check(
+        old,
+        &old.replace("b\n", "B\n"),
+        expect_value!(
+            r#"
+            --- a/src/lib.rs
+            +++ b/src/lib.rs
+            @@ -1,5 +1,5 @@
+             a
+            -b
+            +B
+             c
+             d
+             e
+            "#
+        ),
+    );
+    check(
+        old,
+        &old.replace("b\n", "").replace("k\n", "k\nK\n"),
+        expect_value!(
+            r#"
+            --- a/src/lib.rs
+            +++ b/src/lib.rs
+            @@ -1,5 +1,4 @@
+             a
+            -b
+             c
+             d
+             e
+            @@ -9,4 +8,5 @@
+             i
+             j
+             k
+            +K
+             l
+            "#
+        ),
+    );
+    check(
+        old,
+        &old.replace("e\n", "").replace("i\n", ""),
+        expect_value!(
+            r#"
+            --- a/src/lib.rs
+            +++ b/src/lib.rs
+            @@ -2,11 +2,9 @@
+             b
+             c
+             d
+            -e
+             f
+             g
+             h
+            -i
+             j
+             k
+             l
+            "#
+        ),
+    );
+    check(
+        "a\nb",
+        "a\nc",
+        expect_value!(
+            r#"
+            --- a/src/lib.rs
+            +++ b/src/lib.rs
+            @@ -1,2 +1,2 @@
+             a
+            -b
+            \ No newline at end of file
+            +c
+            \ No newline at end of file
+            "#
+        ),
+    );
+    check(
+        "",
+        "new\n",
+        expect_value!(
+            r#"
+            --- a/src/lib.rs
+            +++ b/src/lib.rs
+            @@ -0,0 +1,1 @@
+            +new
+            "#
+        ),
+    );
+}
+
+#[test]
+pub fn test_expect_scope() {
+    let _scope = ExpectScope::new();
+    for i in 0..3 {
+        expect!(i, "0", "1", "2");
+    }
+}
+
+#[test]
+#[should_panic]
+pub fn test_expect_scope_with_unused_literal() {
+    // Updating would remove the literal instead of failing
+    if UpdateMode::from_env().is_some() {
+        panic!("not checked while updating");
+    }
+    let _scope = ExpectScope::new();
+    for i in 0..2 {
+        expect!(i, "0", "1", "2");
+    }
+}
+
+fn check_one_and_two(one: i32, two: i32) {
+    for actual in [one, two] {
+        expect!(actual, "1", "2");
+    }
+}
+
+#[test]
+pub fn test_assertion_index_per_test() {
+    check_one_and_two(1, 2);
+}
+
+#[test]
+pub fn test_assertion_index_per_other_test() {
+    check_one_and_two(1, 2);
+}
+
+fn check_one_and_two_on_threads() {
+    for actual in [1, 2] {
+        let context = ExpectContext::current();
+        std::thread::spawn(move || {
+            let _context = context.enter();
+            check_one(actual);
+        })
+        .join()
+        .unwrap();
+    }
+}
+
+fn check_one(actual: i32) {
+    expect!(actual, "1", "2");
+}
+
+#[test]
+pub fn test_assertion_index_on_spawned_threads() {
+    check_one_and_two_on_threads();
+}
+
+#[test]
+pub fn test_assertion_index_on_other_test_spawned_threads() {
+    check_one_and_two_on_threads();
+}
+
+#[test]
+pub fn test_expect_scope_restarts_index() {
+    for _ in 0..2 {
+        let _scope = ExpectScope::new();
+        check_one_and_two(1, 2);
+    }
+}
+
+#[test]
+pub fn test_soft_expect_scope() {
+    let _scope = ExpectScope::soft();
+    {
+        let _inner = ExpectScope::new();
+        for i in 0..2 {
+            expect!(i, "0", "1");
+        }
+    }
+    expect!("ABC", "ABC");
+}
+
+#[test]
+#[should_panic(expected = "failed once after both expects ran")]
+pub fn test_soft_expect_scope_with_mismatches() {
+    // Updating would fix the literals instead of failing
+    if UpdateMode::from_env().is_some() {
+        panic!("not checked while updating");
+    }
+    let mut ran = 0;
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _scope = ExpectScope::soft();
+        expect!("one", "1");
+        ran += 1;
+        expect!("two", "2");
+        ran += 1;
+    }));
+    Runtime::forget_failures(file!(), 2);
+    if result.is_err() && ran == 2 {
+        panic!("failed once after both expects ran");
+    }
+}
+
+#[test]
+pub fn test_describe_mismatches() {
+    let position = |line| FilePosition {
+        file: "src/lib.rs",
+        line,
+        column: 9,
+    };
+    let count = |num_expected, calls| SiteCount {
+        num_expected,
+        calls,
+    };
+    let mut sites = std::collections::HashMap::new();
+    sites.insert(position(4), count(2, 2));
+    expect!(describe_mismatches(&sites), "None");
+    sites.insert(position(12), count(5, 1));
+    sites.insert(position(8), count(3, 2));
+    expect!(
+        describe_mismatches(&sites).unwrap(),
+        r#""   src/lib.rs:8:9 ran 2 times but has 3 expected literals\n   src/lib.rs:12:9 ran 1 time but has 5 expected literals\n""#
+    );
+}
+
+#[test]
+fn test_describe_finished_tests() {
+    let finished = FinishedTest {
+        test: "tests::test_loop".to_string(),
+        positions: vec![FilePosition {
+            file: "src/lib.rs",
+            line: 8,
+            column: 9,
+        }],
+        mismatches: "   src/lib.rs:8:9 ran 2 times but has 3 expected literals\n".to_string(),
+    };
+    expect!(
+        describe_finished_tests(&[finished]),
+        "\"   in `tests::test_loop`, which already finished:\\n       src/lib.rs:8:9 ran 2 times but has 3 expected literals\\n\""
+    );
+}
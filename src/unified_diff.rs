@@ -0,0 +1,103 @@
+use std::{collections::HashMap, fmt::Write};
+
+/// Lines of unchanged context around every hunk
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+/// Formats the changes from `old` to `new` as a unified diff with `a/` and
+/// `b/` prefixed paths, like `git diff`. Returns an empty string if nothing
+/// changed.
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    let lines = diff_lines(old, new);
+    let mut hunks: Vec<std::ops::Range<usize>> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if matches!(line, Line::Equal(_)) {
+            continue;
+        }
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + 1 + CONTEXT).min(lines.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.end => hunk.end = end,
+            _ => hunks.push(start..end),
+        }
+    }
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    let mut buf = format!("--- a/{path}\n+++ b/{path}\n");
+    for hunk in hunks {
+        let old_before = count_old(&lines[..hunk.start]);
+        let new_before = count_new(&lines[..hunk.start]);
+        let old_len = count_old(&lines[hunk.clone()]);
+        let new_len = count_new(&lines[hunk.clone()]);
+        // An empty side is numbered by the line before it
+        let old_start = old_before + usize::from(old_len > 0);
+        let new_start = new_before + usize::from(new_len > 0);
+        writeln!(buf, "@@ -{old_start},{old_len} +{new_start},{new_len} @@").unwrap();
+        for line in &lines[hunk] {
+            let (prefix, text) = match line {
+                Line::Equal(text) => (' ', text),
+                Line::Delete(text) => ('-', text),
+                Line::Insert(text) => ('+', text),
+            };
+            buf.push(prefix);
+            buf.push_str(text);
+            if !text.ends_with('\n') {
+                buf.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    buf
+}
+
+fn count_old(lines: &[Line]) -> usize {
+    lines
+        .iter()
+        .filter(|line| !matches!(line, Line::Insert(_)))
+        .count()
+}
+
+fn count_new(lines: &[Line]) -> usize {
+    lines
+        .iter()
+        .filter(|line| !matches!(line, Line::Delete(_)))
+        .count()
+}
+
+/// Diffs line by line, by mapping every distinct line to a char so the
+/// character diff of `dissimilar` can be reused.
+fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<Line<'a>> {
+    let mut ids: HashMap<&str, char> = HashMap::new();
+    let mut lines: Vec<&str> = Vec::new();
+    let mut encode = |text: &'a str| {
+        text.split_inclusive('\n')
+            .map(|line| {
+                *ids.entry(line).or_insert_with(|| {
+                    lines.push(line);
+                    // Private use area, then on past it; never hits the surrogates
+                    char::from_u32(0xE000 + lines.len() as u32 - 1).unwrap()
+                })
+            })
+            .collect::<String>()
+    };
+    let old_encoded = encode(old);
+    let new_encoded = encode(new);
+
+    let mut result = Vec::new();
+    for chunk in dissimilar::diff(&old_encoded, &new_encoded) {
+        let (text, line): (&str, fn(&'a str) -> Line<'a>) = match chunk {
+            dissimilar::Chunk::Equal(text) => (text, Line::Equal),
+            dissimilar::Chunk::Delete(text) => (text, Line::Delete),
+            dissimilar::Chunk::Insert(text) => (text, Line::Insert),
+        };
+        result.extend(text.chars().map(|id| line(lines[id as usize - 0xE000])));
+    }
+    result
+}
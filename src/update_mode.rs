@@ -0,0 +1,65 @@
+/// What `UPDATE_EXPECT` allows the runtime to rewrite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateMode {
+    /// `UPDATE_EXPECT=1` or `UPDATE_EXPECT=all`: overwrite every mismatch
+    All,
+    /// `UPDATE_EXPECT=new`: only fill in assertions without an expected literal
+    New,
+    /// `UPDATE_EXPECT=review`: record mismatches as pending snapshots for `cargo expect review`
+    Review,
+    /// `UPDATE_EXPECT=dry-run`: print a diff of the updated source files instead of writing them
+    DryRun,
+    /// `UPDATE_EXPECT=patch`: write every update to a patch file instead of the source files
+    Patch,
+}
+
+impl UpdateMode {
+    /// Reads `UPDATE_EXPECT`, returning `None` when it is unset.
+    pub fn from_env() -> Option<UpdateMode> {
+        let value = std::env::var("UPDATE_EXPECT").ok()?;
+        Some(UpdateMode::parse(&value).unwrap_or_else(|err| panic!("{}", err)))
+    }
+
+    pub fn parse(value: &str) -> Result<UpdateMode, String> {
+        match value {
+            "1" | "all" => Ok(UpdateMode::All),
+            "new" => Ok(UpdateMode::New),
+            "review" => Ok(UpdateMode::Review),
+            "dry-run" => Ok(UpdateMode::DryRun),
+            "patch" => Ok(UpdateMode::Patch),
+            _ => Err(format!(
+                "Unknown UPDATE_EXPECT value: `{}`
+Expected one of:
+    UPDATE_EXPECT=1        update every failing expect (same as `all`)
+    UPDATE_EXPECT=all      update every failing expect
+    UPDATE_EXPECT=new      only fill in expects that have no expected literal yet
+    UPDATE_EXPECT=review   record pending snapshots for `cargo expect review`
+    UPDATE_EXPECT=dry-run  print a diff of every update without writing it
+    UPDATE_EXPECT=patch    write every update to a patch file for `git apply`",
+                value
+            )),
+        }
+    }
+
+    /// Whether an assertion should be rewritten, given whether it already has an expected literal.
+    pub fn allows_update(self, has_expected: bool) -> bool {
+        match self {
+            UpdateMode::All | UpdateMode::Review | UpdateMode::DryRun | UpdateMode::Patch => true,
+            UpdateMode::New => !has_expected,
+        }
+    }
+
+    /// Whether the literals of an `expect_unordered!` get rewritten at the end
+    /// of the process. Review can't update them, so they fail with an error instead.
+    pub fn rewrites_unordered(self, has_expected: bool) -> bool {
+        self != UpdateMode::Review && self.allows_update(has_expected)
+    }
+
+    /// Whether expected literals that are never reached get removed, instead of failing the test.
+    pub fn removes_surplus(self) -> bool {
+        matches!(
+            self,
+            UpdateMode::All | UpdateMode::DryRun | UpdateMode::Patch
+        )
+    }
+}